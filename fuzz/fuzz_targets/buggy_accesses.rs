@@ -2,7 +2,7 @@
 
 use libfuzzer_sys::fuzz_target;
 use libfuzzer_sys::arbitrary::{Arbitrary, Unstructured};
-use wasm_valgrind::{Valgrind, MemState, AccessError};
+use wasm_valgrind::{Valgrind, MemState, AccessError, Block};
 
 const TEST_MAX_ADDR: usize = 1024 * 640 - 1;
 const TEST_MAX_STACK_SIZE: usize = 1024;
@@ -52,6 +52,9 @@ impl Allocation {
     fn is_in_bounds(&self) -> bool {
         TEST_MAX_STACK_SIZE <= self.addr && self.addr + self.len - 1 <= TEST_MAX_ADDR
     }
+    fn is_in_memory(&self) -> bool {
+        self.addr + self.len - 1 <= TEST_MAX_ADDR
+    }
 }
 
 #[derive(Debug)]
@@ -70,12 +73,14 @@ struct BuggyCommandSequence {
 
 struct BuggyCommandSequenceState {
     allocations: Vec<Allocation>,
+    freed: Vec<Allocation>, // oldest first
 }
 
 impl BuggyCommandSequenceState {
     fn new() -> BuggyCommandSequenceState {
         let allocations = Vec::new();
-        BuggyCommandSequenceState { allocations }
+        let freed = Vec::new();
+        BuggyCommandSequenceState { allocations, freed }
     }
     fn update(&mut self, cmd: &Command) {
         match cmd {
@@ -90,17 +95,19 @@ impl BuggyCommandSequenceState {
                 let validity = is_free_valid(addr, &self);
                 if validity.is_ok() {
                     let index = self.allocations.iter().position(|alloc| alloc.addr == addr).unwrap();
-                    self.allocations.remove(index);
+                    let alloc = self.allocations.remove(index);
+                    self.freed.push(alloc);
                 }
             }
             &Command::Write { addr, len } => {
                 let validity = is_write_valid(addr, len, &self);
                 if validity.is_ok() {
-                    let index = self.allocations.iter().position(|alloc| alloc.addr <= addr && addr + len <= alloc.addr + alloc.len).unwrap();
-                    let alloc_addr = self.allocations[index].addr;
-                    let write_to = &mut self.allocations[index].memstate;
-                    for i in 0..len {
-                        write_to[addr - alloc_addr + i] = MemState::ValidToReadWrite;
+                    for alloc in self.allocations.iter_mut() {
+                        for i in addr..addr + len {
+                            if alloc.addr <= i && i < alloc.addr + alloc.len {
+                                alloc.memstate[i - alloc.addr] = MemState::ValidToReadWrite;
+                            }
+                        }
                     }
                 }
             }
@@ -160,6 +167,22 @@ impl<'a> Arbitrary<'a> for BuggyCommandSequence {
     }
 }
 
+fn nearest_block(addr: usize, state: &BuggyCommandSequenceState) -> Option<Block> {
+    let distance = |alloc: &Allocation| {
+        if alloc.addr <= addr && addr < alloc.addr + alloc.len {
+            0
+        } else if addr >= alloc.addr + alloc.len {
+            addr - (alloc.addr + alloc.len)
+        } else {
+            alloc.addr - addr
+        }
+    };
+    // ties go to the lower block
+    state.allocations.iter()
+        .min_by_key(|alloc| (distance(alloc), alloc.addr))
+        .map(|alloc| Block::new(alloc.addr, alloc.len))
+}
+
 fn no_allocs_in_range(state: &BuggyCommandSequenceState, other: &Allocation ) -> bool {
     state.allocations.iter().all(|alloc| alloc.no_overlaps(other))
 }
@@ -176,7 +199,11 @@ fn is_malloc_valid(alloc: &Allocation, state: &BuggyCommandSequenceState) -> Res
 
 fn is_free_valid(addr: usize, state: &BuggyCommandSequenceState) -> Result<(), AccessError> {
     if !state.allocations.iter().any(|alloc| alloc.addr == addr) {
-        return Err(AccessError::InvalidFree { addr });
+        let freed_block = match state_at(addr, &state) {
+            MemState::Freed => Some(freed_block(addr, &state)),
+            _ => None,
+        };
+        return Err(AccessError::InvalidFree { addr, block: nearest_block(addr, &state), freed_block });
     } else { 
         return Ok(());
     }
@@ -184,35 +211,48 @@ fn is_free_valid(addr: usize, state: &BuggyCommandSequenceState) -> Result<(), A
 
 fn is_read_valid(addr: usize, len: usize, state: &BuggyCommandSequenceState) -> Result<(), AccessError> {
     let dummy = Allocation::new(addr, len);
-    if !dummy.is_in_bounds() {
+    if !dummy.is_in_memory() {
         return Err(AccessError::OutOfBounds { addr, len });
     }
-    let in_range: Vec<_> = state.allocations.iter()
-                                    .filter(|alloc| alloc.addr <= addr && 
-                                        addr + len <= alloc.addr + alloc.len && alloc.memstate.contains(&MemState::ValidToReadWrite)).collect();
-    if in_range.is_empty() {
-        return Err(AccessError::InvalidRead { addr, len });
-    } else {
-        let memstate_addr = addr - &in_range[0].addr;
-        for i in memstate_addr..memstate_addr + len {
-            // println!("{:?}", mem_index);
-            if in_range[0].memstate[i] != MemState::ValidToReadWrite {
-                return Err(AccessError::InvalidRead { addr, len });
-            }
+    for bad_addr in addr..addr + len {
+        let memstate = state_at(bad_addr, &state);
+        if memstate == MemState::Freed {
+            return Err(AccessError::UseAfterFree { addr, len, freed_block: freed_block(bad_addr, &state) });
+        }
+        if memstate != MemState::ValidToReadWrite {
+            return Err(AccessError::InvalidRead { addr, len, bad_addr, state: memstate, block: nearest_block(bad_addr, &state), origin: None });
         }
-        return Ok(());
     }
+    return Ok(());
 }
 
 fn is_write_valid(addr: usize, len: usize, state: &BuggyCommandSequenceState) -> Result<(), AccessError> {
     let dummy = Allocation::new(addr, len);
     //this doesn't include stack... have to change to include validity for stack read/writes
-    if !dummy.is_in_bounds() {
+    if !dummy.is_in_memory() {
         return Err(AccessError::OutOfBounds { addr, len });
     }
-    if !state.allocations.iter().any(|alloc| alloc.addr <= addr && addr + len <= alloc.addr + alloc.len) {
-        return Err(AccessError::InvalidWrite { addr, len });
-    } else { 
-        return Ok(());
+    for bad_addr in addr..addr + len {
+        let memstate = state_at(bad_addr, &state);
+        if memstate == MemState::Freed {
+            return Err(AccessError::UseAfterFree { addr, len, freed_block: freed_block(bad_addr, &state) });
+        }
+        if memstate == MemState::Unallocated {
+            return Err(AccessError::InvalidWrite { addr, len, bad_addr, state: memstate, block: nearest_block(bad_addr, &state) });
+        }
     }
-}
\ No newline at end of file
+    return Ok(());
+}
+
+fn state_at(addr: usize, state: &BuggyCommandSequenceState) -> MemState {
+    match state.allocations.iter().find(|alloc| alloc.addr <= addr && addr < alloc.addr + alloc.len) {
+        Some(alloc) => alloc.memstate[addr - alloc.addr],
+        None if state.freed.iter().any(|alloc| alloc.addr <= addr && addr < alloc.addr + alloc.len) => MemState::Freed,
+        None => MemState::Unallocated,
+    }
+}
+
+fn freed_block(addr: usize, state: &BuggyCommandSequenceState) -> Block {
+    let alloc = state.freed.iter().rev().find(|alloc| alloc.addr <= addr && addr < alloc.addr + alloc.len).unwrap();
+    Block::new(alloc.addr, alloc.len)
+}
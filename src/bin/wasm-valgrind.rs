@@ -0,0 +1,343 @@
+/*
+Runs a WASI module under memcheck: the module is instrumented, executed with
+Wasmtime, and every error plus a leak summary is printed to stderr in the style
+of valgrind, except for those matched by a suppression file. Modules built with debug
+info have their frames printed as source locations. The same report can also be
+written as JSON or SARIF. The exit code is the guest's, or `--error-exitcode` if any
+error was found.
+*/
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use wasm_valgrind::dwarf::DebugInfo;
+use wasm_valgrind::instrument::{self, AllocatorNames, Symbols};
+use wasm_valgrind::report::Report;
+use wasm_valgrind::wasmtime::{add_to_linker, global_roots, ErrorPolicy, ValgrindCtx};
+use wasm_valgrind::{
+    AccessError, ErrorSummary, LoggedError, Monitor, Suppressions, UndefinedChecks, UsePoints,
+    Valgrind,
+};
+use wasmtime::{Engine, Linker, Module, Store};
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use wasmtime_wasi::{DirPerms, FilePerms, I32Exit, WasiCtxBuilder};
+
+#[derive(Parser)]
+#[command(
+    name = "wasm-valgrind",
+    about = "Run a WASI module under wasm-valgrind"
+)]
+struct Args {
+    /// Stop at the first error instead of reporting every error.
+    #[arg(long)]
+    trap_on_error: bool,
+    /// Don't wrap the module's malloc/free family.
+    #[arg(long)]
+    no_malloc: bool,
+    /// Number of callers recorded in the stacks of errors and blocks; 0 turns call
+    /// tracking off.
+    #[arg(long, default_value_t = 12, value_name = "N")]
+    num_callers: usize,
+    /// Report uninitialized values where they decide a branch, select an indirect call
+    /// or address memory, rather than wherever they are loaded. Values passed to or
+    /// returned from indirect calls count as initialized.
+    #[arg(long)]
+    check_on_use: bool,
+    /// Report where the bytes of uninitialized reads were allocated.
+    #[arg(long)]
+    track_origins: bool,
+    /// Skip the leak check at exit.
+    #[arg(long)]
+    no_leak_check: bool,
+    /// Exit code to use when errors were found.
+    #[arg(long, default_value_t = 1)]
+    error_exitcode: u8,
+    /// Suppress the errors described in FILE (valgrind's format); may be repeated.
+    #[arg(long = "suppressions", value_name = "FILE")]
+    suppressions: Vec<PathBuf>,
+    /// Also write the errors and leaks as JSON to FILE.
+    #[arg(long, value_name = "FILE")]
+    json_file: Option<PathBuf>,
+    /// Also write the errors and leaks as a SARIF 2.1.0 log to FILE.
+    #[arg(long, value_name = "FILE")]
+    sarif_file: Option<PathBuf>,
+    /// Host directory to make available to the guest under the same path.
+    #[arg(long = "dir", value_name = "DIR")]
+    dirs: Vec<PathBuf>,
+    /// The WASI module to run.
+    module: PathBuf,
+    /// Arguments passed to the module.
+    #[arg(trailing_var_arg = true)]
+    args: Vec<String>,
+}
+
+struct Host {
+    wasi: WasiP1Ctx,
+    valgrind: ValgrindCtx,
+}
+
+/// Prefixes every line like valgrind does.
+macro_rules! report {
+    ($($arg:tt)*) => {
+        eprintln!("=={}== {}", std::process::id(), format!($($arg)*))
+    };
+}
+
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(code) => ExitCode::from(code),
+        Err(err) => {
+            eprintln!("wasm-valgrind: {:#}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> Result<u8> {
+    let wasm = std::fs::read(&args.module)
+        .with_context(|| format!("failed to read {}", args.module.display()))?;
+    let mut config = instrument::Config::new();
+    if !args.no_malloc {
+        config.set_allocator(AllocatorNames::default());
+    }
+    config.set_call_stacks(args.num_callers > 0);
+    config.set_moves(args.check_on_use);
+    config.set_value_shadows(args.check_on_use);
+    config.set_global_roots(!args.no_leak_check);
+    let instrumented = instrument::instrument(&wasm, &config)?;
+    let layout = instrument::layout(&wasm, &config)?;
+    let symbols = instrument::symbols(&wasm)?;
+    let debug_info = DebugInfo::parse(&wasm)?;
+    let names = Names {
+        symbols: &symbols,
+        debug_info: &debug_info,
+    };
+    let mut suppressions = Suppressions::default();
+    for path in &args.suppressions {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let file = Suppressions::parse(&text)
+            .with_context(|| format!("invalid suppressions in {}", path.display()))?;
+        suppressions.extend(file);
+    }
+
+    let mut wasi = WasiCtxBuilder::new();
+    wasi.inherit_stdio().inherit_env();
+    wasi.arg(args.module.display().to_string()).args(&args.args);
+    for dir in &args.dirs {
+        let guest_path = dir.display().to_string();
+        wasi.preopened_dir(dir, guest_path, DirPerms::all(), FilePerms::all())?;
+    }
+    let policy = if args.trap_on_error {
+        ErrorPolicy::Trap
+    } else {
+        ErrorPolicy::Collect
+    };
+    let mut valgrind = ValgrindCtx::new(layout, policy);
+    valgrind.set_stack_depth(args.num_callers);
+    if args.check_on_use {
+        valgrind.set_undefined_checks(UndefinedChecks::OnUse(UsePoints::default()));
+    }
+    valgrind.set_track_origins(args.track_origins);
+    if !args.suppressions.is_empty() {
+        valgrind.set_suppressions(suppressions, symbols.clone());
+    }
+    let host = Host {
+        wasi: wasi.build_p1(),
+        valgrind,
+    };
+
+    let engine = Engine::default();
+    let module = Module::new(&engine, &instrumented)?;
+    let mut linker = Linker::new(&engine);
+    preview1::add_to_linker_sync(&mut linker, |host: &mut Host| &mut host.wasi)?;
+    add_to_linker(&mut linker, |host: &mut Host| &mut host.valgrind)?;
+    let mut store = Store::new(&engine, host);
+    let instance = linker.instantiate(&mut store, &module)?;
+    let start = instance.get_typed_func::<(), ()>(&mut store, "_start")?;
+
+    report!("wasm-valgrind, a memory error detector for WebAssembly");
+    let command = std::iter::once(args.module.display().to_string())
+        .chain(args.args.iter().cloned())
+        .collect::<Vec<_>>();
+    report!("Command: {}", command.join(" "));
+    report!("");
+
+    let mut logged = Vec::new();
+    let exit_code = match start.call(&mut store, ()) {
+        Ok(()) => 0,
+        Err(err) => {
+            if let Some(exit) = err.downcast_ref::<I32Exit>() {
+                exit.0 as u8
+            } else if let Some(err) = err.downcast_ref::<AccessError>() {
+                // the guest stopped inside the hook, so its site and call stack are current
+                let valgrind = store.data().valgrind.monitor().map(Monitor::valgrind);
+                logged.push(LoggedError {
+                    error: err.clone(),
+                    site: valgrind.and_then(Valgrind::site),
+                    stack: valgrind.map(Valgrind::call_stack).unwrap_or_default(),
+                    count: 1,
+                });
+                args.error_exitcode
+            } else {
+                report!("Guest trapped: {:?}", err);
+                args.error_exitcode
+            }
+        }
+    };
+    if let Some(log) = store.data().valgrind.error_log() {
+        logged.extend(log.errors().iter().cloned());
+    }
+    for logged in &logged {
+        report_error(&logged.error, logged.site, &logged.stack, &names);
+        if logged.count > 1 {
+            report!("   (seen {} times)", logged.count);
+        }
+    }
+    let mut errors = ErrorSummary {
+        errors: logged.iter().map(|logged| logged.count).sum(),
+        contexts: logged.len(),
+    };
+
+    let memory = instance.get_memory(&mut store, "memory");
+    let roots = global_roots(&instance, &mut store);
+    let mut leaks = None;
+    if let (false, Some(memory), Some(monitor)) =
+        (args.no_leak_check, memory, store.data().valgrind.monitor())
+    {
+        let leaks = leaks.insert(monitor.valgrind().leak_check(memory.data(&store), &roots));
+        let lost = leaks.definitely_lost();
+        let possibly = leaks.possibly_lost();
+        let reachable = leaks.still_reachable();
+        report!("");
+        report!("LEAK SUMMARY:");
+        report!(
+            "   definitely lost: {} bytes in {} blocks",
+            lost.bytes,
+            lost.blocks
+        );
+        report!(
+            "     possibly lost: {} bytes in {} blocks",
+            possibly.bytes,
+            possibly.blocks
+        );
+        report!(
+            "   still reachable: {} bytes in {} blocks",
+            reachable.bytes,
+            reachable.blocks
+        );
+        errors.errors += lost.blocks + possibly.blocks;
+        errors.contexts += lost.blocks + possibly.blocks;
+    }
+    report!("");
+    let suppressed = store
+        .data()
+        .valgrind
+        .monitor()
+        .and_then(|monitor| monitor.valgrind().suppressions());
+    for (name, count) in suppressed.into_iter().flat_map(Suppressions::fired) {
+        if count > 0 {
+            report!("used_suppression: {:6} {}", count, name);
+        }
+    }
+    let suppressed = suppressed.map(Suppressions::summary).unwrap_or_default();
+    report!(
+        "ERROR SUMMARY: {} errors from {} contexts (suppressed: {} from {})",
+        errors.errors,
+        errors.contexts,
+        suppressed.errors,
+        suppressed.contexts
+    );
+
+    let module = args.module.display().to_string();
+    let mut report = Report::new(&logged);
+    report
+        .set_symbols(&symbols)
+        .set_debug_info(&debug_info)
+        .set_module(&module);
+    if let Some(leaks) = &leaks {
+        report.set_leaks(leaks);
+    }
+    if let Some(path) = &args.json_file {
+        write_report(path, &report.to_json())?;
+    }
+    if let Some(path) = &args.sarif_file {
+        write_report(path, &report.to_sarif())?;
+    }
+
+    if errors.errors > 0 {
+        Ok(args.error_exitcode)
+    } else {
+        Ok(exit_code)
+    }
+}
+
+fn write_report(path: &Path, report: &serde_json::Value) -> Result<()> {
+    let text = serde_json::to_string_pretty(report)?;
+    std::fs::write(path, text).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// What frames are printed as: source locations if the module has DWARF, else names.
+struct Names<'a> {
+    symbols: &'a Symbols,
+    debug_info: &'a DebugInfo,
+}
+
+fn report_error(err: &AccessError, site: Option<usize>, stack: &[u32], names: &Names) {
+    report!("{}", err);
+    // the site gives the exact location of the first frame, with what was inlined there
+    let inlined = site
+        .map(|site| names.debug_info.locate(site))
+        .unwrap_or_default();
+    let mut depth = 0;
+    for frame in &inlined {
+        report_frame(depth, &frame.to_string());
+        depth += 1;
+    }
+    let outer = if inlined.is_empty() {
+        stack
+    } else {
+        stack.get(1..).unwrap_or(&[])
+    };
+    report_stack(depth, outer, names);
+    if let Some(block) = err.block() {
+        if !block.free_stack.is_empty() {
+            report!(" Block was free'd at");
+            report_stack(0, &block.free_stack, names);
+        }
+        if !block.alloc_stack.is_empty() {
+            report!(" Block was alloc'd at");
+            report_stack(0, &block.alloc_stack, names);
+        }
+    }
+    if let Some(func) = err.frame().and_then(|frame| frame.func) {
+        report!(" Stack frame was claimed by");
+        report_stack(0, &[func], names);
+    }
+    if let Some(origin) = err.origin() {
+        report!(" {}", origin);
+        report_stack(0, &origin.stack, names);
+    }
+    report!("");
+}
+
+/// Reports `stack` as the frames from `depth` on.
+fn report_stack(depth: usize, stack: &[u32], names: &Names) {
+    for (depth, func) in (depth..).zip(stack) {
+        let frame = match (
+            names.debug_info.locate_function(*func),
+            names.symbols.name(*func),
+        ) {
+            (Some(frame), _) => format!("{} (function {})", frame, func),
+            (None, Some(name)) => format!("{} (function {})", name, func),
+            (None, None) => format!("function {}", func),
+        };
+        report_frame(depth, &frame);
+    }
+}
+
+fn report_frame(depth: usize, frame: &str) {
+    let how = if depth == 0 { "at" } else { "by" };
+    report!("   {} {}", how, frame);
+}
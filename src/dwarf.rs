@@ -0,0 +1,304 @@
+/*
+Source locations from the DWARF that compilers embed in wasm modules as `.debug_*`
+custom sections. DWARF addresses in wasm are offsets from the start of the code
+section's contents, so module offsets such as sites are shifted before the lookup.
+
+A site resolves to the chain of functions inlined at that instruction. Stacks only
+record function indices, not call sites, so a function resolves to the location of
+its first instruction.
+*/
+
+use addr2line::Context;
+use gimli::{EndianArcSlice, LittleEndian};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use wasmparser::{Parser, Payload, TypeRef};
+
+type Reader = EndianArcSlice<LittleEndian>;
+
+#[derive(Debug)]
+pub enum DwarfError {
+    Parse(wasmparser::BinaryReaderError),
+    Dwarf(gimli::Error),
+}
+
+impl fmt::Display for DwarfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DwarfError::Parse(err) => write!(f, "failed to parse module: {}", err),
+            DwarfError::Dwarf(err) => write!(f, "invalid DWARF: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DwarfError {}
+
+impl From<wasmparser::BinaryReaderError> for DwarfError {
+    fn from(err: wasmparser::BinaryReaderError) -> Self {
+        DwarfError::Parse(err)
+    }
+}
+
+impl From<gimli::Error> for DwarfError {
+    fn from(err: gimli::Error) -> Self {
+        DwarfError::Dwarf(err)
+    }
+}
+
+/// One source-level frame; inlined calls give several frames for one instruction.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceFrame {
+    pub function: Option<String>, // demangled
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+impl fmt::Display for SourceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.function.as_deref().unwrap_or("???"))?;
+        if let Some(file) = &self.file {
+            write!(f, " ({}", file)?;
+            if let Some(line) = self.line {
+                write!(f, ":{}", line)?;
+                if let Some(column) = self.column.filter(|column| *column > 0) {
+                    write!(f, ":{}", column)?;
+                }
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct DebugInfo {
+    context: Option<Context<Reader>>, // None for modules without DWARF
+    code_start: usize,
+    imported_funcs: u32,
+    entries: Vec<usize>, // offset of the first instruction of each defined function
+}
+
+impl DebugInfo {
+    /// Reads the DWARF of `wasm`, which must be the module the sites refer to, i.e. the
+    /// original module rather than the instrumented one.
+    pub fn parse(wasm: &[u8]) -> Result<DebugInfo, DwarfError> {
+        let mut sections = HashMap::new();
+        let mut code_start = 0;
+        let mut imported_funcs = 0;
+        let mut entries = Vec::new();
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload? {
+                Payload::ImportSection(section) => {
+                    for import in section.into_imports() {
+                        if let TypeRef::Func(_) | TypeRef::FuncExact(_) = import?.ty {
+                            imported_funcs += 1;
+                        }
+                    }
+                }
+                Payload::CodeSectionStart { range, .. } => code_start = range.start,
+                Payload::CodeSectionEntry(body) => {
+                    entries.push(body.get_operators_reader()?.original_position());
+                }
+                Payload::CustomSection(section) if section.name().starts_with(".debug_") => {
+                    sections.insert(
+                        section.name().to_string(),
+                        Arc::<[u8]>::from(section.data()),
+                    );
+                }
+                _ => {}
+            }
+        }
+        let context = if sections.contains_key(".debug_info") {
+            let dwarf = gimli::Dwarf::load(|id| {
+                let data = sections
+                    .get(id.name())
+                    .cloned()
+                    .unwrap_or_else(|| Arc::from(&[][..]));
+                Ok::<_, gimli::Error>(EndianArcSlice::new(data, LittleEndian))
+            })?;
+            Some(Context::from_dwarf(dwarf)?)
+        } else {
+            None
+        };
+        Ok(DebugInfo {
+            context,
+            code_start,
+            imported_funcs,
+            entries,
+        })
+    }
+    pub fn has_dwarf(&self) -> bool {
+        self.context.is_some()
+    }
+    /// The frames at the module offset `site`, innermost first. Empty if the DWARF does
+    /// not cover it.
+    pub fn locate(&self, site: usize) -> Vec<SourceFrame> {
+        let (Some(context), Some(address)) = (&self.context, site.checked_sub(self.code_start))
+        else {
+            return Vec::new();
+        };
+        let mut frames = Vec::new();
+        // lookups are best effort: malformed DWARF just ends the chain
+        let Ok(mut iter) = context.find_frames(address as u64).skip_all_loads() else {
+            return frames;
+        };
+        while let Ok(Some(frame)) = iter.next() {
+            let function = frame
+                .function
+                .and_then(|name| name.demangle().ok().map(|name| name.into_owned()));
+            let location = frame.location;
+            frames.push(SourceFrame {
+                function,
+                file: location.as_ref().and_then(|l| l.file).map(str::to_string),
+                line: location.as_ref().and_then(|l| l.line),
+                column: location.as_ref().and_then(|l| l.column),
+            });
+        }
+        frames
+    }
+    /// The location of the first instruction of function `func`, without the functions
+    /// inlined there.
+    pub fn locate_function(&self, func: u32) -> Option<SourceFrame> {
+        let index = func.checked_sub(self.imported_funcs)?;
+        let entry = *self.entries.get(index as usize)?;
+        self.locate(entry).pop()
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn module_with_dwarf() -> (Vec<u8>, Vec<usize>) {
+    use gimli::write::{
+        Address, AttributeValue, DwarfUnit, EndianVec, LineProgram, LineString, Sections,
+    };
+
+    let wasm = wat::parse_str(
+        r#"(module
+            (memory 1)
+            (func $outer (i32.store (i32.const 0) (i32.const 1))
+                         (drop (i32.load (i32.const 0)))))"#,
+    )
+    .unwrap();
+    // offsets of the code section contents, the function's entry, the store and the load
+    let mut code_start = 0;
+    let mut entry = 0;
+    let mut sites = Vec::new();
+    for payload in Parser::new(0).parse_all(&wasm) {
+        match payload.unwrap() {
+            Payload::CodeSectionStart { range, .. } => code_start = range.start,
+            Payload::CodeSectionEntry(body) => {
+                let mut reader = body.get_operators_reader().unwrap();
+                entry = reader.original_position();
+                while !reader.eof() {
+                    let (op, offset) = reader.read_with_offset().unwrap();
+                    if let wasmparser::Operator::I32Store { .. }
+                    | wasmparser::Operator::I32Load { .. } = op
+                    {
+                        sites.push(offset);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let address = |site: usize| Address::Constant((site - code_start) as u64);
+
+    let encoding = gimli::Encoding {
+        format: gimli::Format::Dwarf32,
+        version: 4,
+        address_size: 4,
+    };
+    let mut dwarf = DwarfUnit::new(encoding);
+    let mut program = LineProgram::new(
+        encoding,
+        gimli::LineEncoding::default(),
+        LineString::String(Vec::new()),
+        None,
+        LineString::String(b"main.c".to_vec()),
+        None,
+    );
+    let dir = program.default_directory();
+    let file = program.add_file(LineString::String(b"main.c".to_vec()), dir, None);
+    program.begin_sequence(Some(address(entry)));
+    for (site, line) in [(entry, 3), (sites[0], 4), (sites[1], 10)] {
+        program.row().address_offset = (site - entry) as u64;
+        program.row().file = file;
+        program.row().line = line;
+        program.row().column = 3;
+        program.generate_row();
+    }
+    program.end_sequence((sites[1] + 1 - entry) as u64);
+    dwarf.unit.line_program = program;
+
+    let root = dwarf.unit.root();
+    let outer = dwarf.unit.add(root, gimli::DW_TAG_subprogram);
+    let die = dwarf.unit.get_mut(outer);
+    die.set(gimli::DW_AT_name, AttributeValue::String(b"outer".to_vec()));
+    die.set(gimli::DW_AT_low_pc, AttributeValue::Address(address(entry)));
+    die.set(
+        gimli::DW_AT_high_pc,
+        AttributeValue::Udata((sites[1] + 1 - entry) as u64),
+    );
+    let inlined = dwarf.unit.add(outer, gimli::DW_TAG_inlined_subroutine);
+    let die = dwarf.unit.get_mut(inlined);
+    die.set(
+        gimli::DW_AT_name,
+        AttributeValue::String(b"helper".to_vec()),
+    );
+    die.set(
+        gimli::DW_AT_low_pc,
+        AttributeValue::Address(address(sites[1])),
+    );
+    die.set(gimli::DW_AT_high_pc, AttributeValue::Udata(1));
+    die.set(
+        gimli::DW_AT_call_file,
+        AttributeValue::FileIndex(Some(file)),
+    );
+    die.set(gimli::DW_AT_call_line, AttributeValue::Udata(5));
+
+    let mut sections = Sections::new(EndianVec::new(LittleEndian));
+    dwarf.write(&mut sections).unwrap();
+    let mut module = wasm.clone();
+    sections
+        .for_each(|id, data| {
+            if !data.slice().is_empty() {
+                let section = wasm_encoder::CustomSection {
+                    name: id.name().into(),
+                    data: data.slice().into(),
+                };
+                wasm_encoder::Section::append_to(&section, &mut module);
+            }
+            Ok::<_, gimli::write::Error>(())
+        })
+        .unwrap();
+    (module, sites)
+}
+
+#[test]
+fn locates_sites_and_inlined_frames() {
+    let (wasm, sites) = module_with_dwarf();
+    let info = DebugInfo::parse(&wasm).unwrap();
+    assert!(info.has_dwarf());
+
+    let frames = info.locate(sites[0]);
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].to_string(), "outer (main.c:4:3)");
+
+    // the load is in helper, inlined into outer from line 5
+    let frames = info.locate(sites[1]);
+    let frames = frames.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+    assert_eq!(frames, ["helper (main.c:10:3)", "outer (main.c:5)"]);
+
+    let entry = info.locate_function(0).unwrap();
+    assert_eq!(entry.to_string(), "outer (main.c:3:3)");
+    assert_eq!(info.locate_function(1), None);
+}
+
+#[test]
+fn modules_without_dwarf() {
+    let wasm = wat::parse_str("(module (func (nop)))").unwrap();
+    let info = DebugInfo::parse(&wasm).unwrap();
+    assert!(!info.has_dwarf());
+    assert_eq!(info.locate(20), []);
+    assert_eq!(info.locate_function(0), None);
+}
@@ -0,0 +1,547 @@
+use crate::{MemState, Origin, StackFrame, UsePoint, ValueUse};
+use std::cmp::max;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessError {
+    DoubleMalloc {
+        addr: usize,
+        len: usize,
+    },
+    InvalidRead {
+        addr: usize,
+        len: usize,
+        bad_addr: usize,        // first offending byte
+        state: MemState,        // state of the first offending byte
+        block: Option<Block>,   // block containing or nearest to `bad_addr`
+        origin: Option<Origin>, // allocation that left `bad_addr` undefined, with origin tracking
+    },
+    InvalidWrite {
+        addr: usize,
+        len: usize,
+        bad_addr: usize,      // first offending byte
+        state: MemState,      // state of the first offending byte
+        block: Option<Block>, // block containing or nearest to `bad_addr`
+    },
+    InvalidFree {
+        addr: usize,
+        block: Option<Block>,
+        freed_block: Option<Block>, // the block `addr` was freed from, on a double free
+    },
+    UseAfterFree {
+        addr: usize,
+        len: usize,
+        freed_block: Block,
+    },
+    /// An access to a stack frame that was popped by raising the stack pointer.
+    UseAfterReturn {
+        addr: usize,
+        len: usize,
+        frame: StackFrame,
+    },
+    HeapBufferOverflow {
+        addr: usize,
+        len: usize,
+        bad_addr: usize, // first byte that hit a redzone
+        block: Block,    // block whose redzone was hit
+        position: BlockPosition,
+    },
+    QuarantinedMalloc {
+        addr: usize,
+        len: usize,
+        freed_block: Block,
+    },
+    OutOfBounds {
+        addr: usize,
+        len: usize,
+    },
+    /// Undefined bytes reached a use point; `addr..addr + len` are the bytes being used.
+    UninitializedUse {
+        point: UsePoint,
+        addr: usize,
+        len: usize,
+        bad_addr: usize,        // first undefined byte
+        block: Option<Block>,   // block containing or nearest to `bad_addr`
+        origin: Option<Origin>, // allocation that left `bad_addr` undefined, with origin tracking
+    },
+    /// An undefined value decided a branch, indexed a table or addressed memory.
+    UndefinedValue {
+        point: ValueUse,
+    },
+    CallocOverflow {
+        nmemb: usize,
+        size: usize,
+    },
+    InvalidGrow {
+        cur_pages: usize,
+        delta_pages: usize,
+        max_pages: usize,
+    },
+}
+
+impl AccessError {
+    /// Name of the variant, e.g. `"InvalidRead"`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AccessError::DoubleMalloc { .. } => "DoubleMalloc",
+            AccessError::InvalidRead { .. } => "InvalidRead",
+            AccessError::InvalidWrite { .. } => "InvalidWrite",
+            AccessError::InvalidFree { .. } => "InvalidFree",
+            AccessError::UseAfterFree { .. } => "UseAfterFree",
+            AccessError::UseAfterReturn { .. } => "UseAfterReturn",
+            AccessError::HeapBufferOverflow { .. } => "HeapBufferOverflow",
+            AccessError::QuarantinedMalloc { .. } => "QuarantinedMalloc",
+            AccessError::OutOfBounds { .. } => "OutOfBounds",
+            AccessError::UninitializedUse { .. } => "UninitializedUse",
+            AccessError::UndefinedValue { .. } => "UndefinedValue",
+            AccessError::CallocOverflow { .. } => "CallocOverflow",
+            AccessError::InvalidGrow { .. } => "InvalidGrow",
+        }
+    }
+    /// The block the error is about: the freed block for errors involving freed memory,
+    /// else the block containing or nearest to the offending address.
+    pub fn block(&self) -> Option<&Block> {
+        match self {
+            AccessError::InvalidRead { block, .. }
+            | AccessError::InvalidWrite { block, .. }
+            | AccessError::UninitializedUse { block, .. } => block.as_ref(),
+            AccessError::InvalidFree {
+                block, freed_block, ..
+            } => freed_block.as_ref().or(block.as_ref()),
+            AccessError::UseAfterFree { freed_block, .. }
+            | AccessError::QuarantinedMalloc { freed_block, .. } => Some(freed_block),
+            AccessError::HeapBufferOverflow { block, .. } => Some(block),
+            AccessError::DoubleMalloc { .. }
+            | AccessError::UseAfterReturn { .. }
+            | AccessError::OutOfBounds { .. }
+            | AccessError::UndefinedValue { .. }
+            | AccessError::CallocOverflow { .. }
+            | AccessError::InvalidGrow { .. } => None,
+        }
+    }
+    /// Where the undefined bytes of an uninitialised read came from, with origin tracking.
+    pub fn origin(&self) -> Option<&Origin> {
+        match self {
+            AccessError::InvalidRead { origin, .. }
+            | AccessError::UninitializedUse { origin, .. } => origin.as_ref(),
+            _ => None,
+        }
+    }
+    /// The returned stack frame of a use after return.
+    pub fn frame(&self) -> Option<&StackFrame> {
+        match self {
+            AccessError::UseAfterReturn { frame, .. } => Some(frame),
+            _ => None,
+        }
+    }
+    /// Start of the offending access, allocation or free, if the error has one.
+    pub fn addr(&self) -> Option<usize> {
+        match self {
+            AccessError::DoubleMalloc { addr, .. }
+            | AccessError::InvalidRead { addr, .. }
+            | AccessError::InvalidWrite { addr, .. }
+            | AccessError::InvalidFree { addr, .. }
+            | AccessError::UseAfterFree { addr, .. }
+            | AccessError::UseAfterReturn { addr, .. }
+            | AccessError::HeapBufferOverflow { addr, .. }
+            | AccessError::QuarantinedMalloc { addr, .. }
+            | AccessError::OutOfBounds { addr, .. }
+            | AccessError::UninitializedUse { addr, .. } => Some(*addr),
+            AccessError::UndefinedValue { .. }
+            | AccessError::CallocOverflow { .. }
+            | AccessError::InvalidGrow { .. } => None,
+        }
+    }
+    /// Size in bytes of the offending access or allocation, if the error has one.
+    pub fn size(&self) -> Option<usize> {
+        match self {
+            AccessError::DoubleMalloc { len, .. }
+            | AccessError::InvalidRead { len, .. }
+            | AccessError::InvalidWrite { len, .. }
+            | AccessError::UseAfterFree { len, .. }
+            | AccessError::UseAfterReturn { len, .. }
+            | AccessError::HeapBufferOverflow { len, .. }
+            | AccessError::QuarantinedMalloc { len, .. }
+            | AccessError::OutOfBounds { len, .. }
+            | AccessError::UninitializedUse { len, .. } => Some(*len),
+            AccessError::InvalidFree { .. }
+            | AccessError::UndefinedValue { .. }
+            | AccessError::CallocOverflow { .. }
+            | AccessError::InvalidGrow { .. } => None,
+        }
+    }
+}
+
+/// A heap block from the allocation table, used to describe where a bad address lies.
+/// The stacks hold wasm function indices, innermost first, and are empty unless the
+/// module reports its calls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub addr: usize,
+    pub len: usize,
+    pub alloc_stack: Box<[u32]>,
+    pub free_stack: Box<[u32]>,
+}
+
+/// Where an address lies relative to a `Block`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockPosition {
+    Inside { offset: usize },
+    Before { distance: usize },
+    After { distance: usize }, // distance from the end of the block
+}
+
+impl Block {
+    pub fn new(addr: usize, len: usize) -> Block {
+        Block {
+            addr,
+            len,
+            alloc_stack: Box::default(),
+            free_stack: Box::default(),
+        }
+    }
+    pub fn position(&self, addr: usize) -> BlockPosition {
+        let end = self.addr + self.len;
+        if self.addr <= addr && addr < end {
+            BlockPosition::Inside {
+                offset: addr - self.addr,
+            }
+        } else if addr >= end {
+            BlockPosition::After {
+                distance: addr - end,
+            }
+        } else {
+            BlockPosition::Before {
+                distance: self.addr - addr,
+            }
+        }
+    }
+    fn describe(&self, f: &mut fmt::Formatter<'_>, addr: usize, how: &str) -> fmt::Result {
+        match self.position(addr) {
+            BlockPosition::Inside { offset } => write!(f, ", {} bytes inside", offset)?,
+            BlockPosition::Before { distance } => write!(f, ", {} bytes before", distance)?,
+            BlockPosition::After { distance } => write!(f, ", {} bytes after", distance)?,
+        }
+        write!(f, " a block of size {} {}", self.len, how)
+    }
+}
+
+fn describe_state(state: MemState) -> &'static str {
+    match state {
+        MemState::Unallocated => "unallocated",
+        MemState::ValidToWrite => "uninitialized",
+        MemState::ValidToReadWrite => "initialized",
+        MemState::Freed => "freed",
+        MemState::Redzone => "in a redzone",
+    }
+}
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (addr, block) = match self {
+            AccessError::DoubleMalloc { addr, len } => {
+                return write!(
+                    f,
+                    "Allocation of size {} at {:#x} overlaps a live block",
+                    len, addr
+                );
+            }
+            AccessError::InvalidRead {
+                addr,
+                len,
+                bad_addr,
+                state,
+                block,
+                ..
+            } => {
+                write!(
+                    f,
+                    "Invalid read of size {} at {:#x}: byte {:#x} is {}",
+                    len,
+                    addr,
+                    bad_addr,
+                    describe_state(*state)
+                )?;
+                (*bad_addr, block)
+            }
+            AccessError::InvalidWrite {
+                addr,
+                len,
+                bad_addr,
+                state,
+                block,
+            } => {
+                write!(
+                    f,
+                    "Invalid write of size {} at {:#x}: byte {:#x} is {}",
+                    len,
+                    addr,
+                    bad_addr,
+                    describe_state(*state)
+                )?;
+                (*bad_addr, block)
+            }
+            AccessError::InvalidFree {
+                addr,
+                block,
+                freed_block,
+            } => {
+                write!(f, "Invalid free() at {:#x}", addr)?;
+                if let Some(freed_block) = freed_block {
+                    return freed_block.describe(f, *addr, "free'd");
+                }
+                (*addr, block)
+            }
+            AccessError::UseAfterFree {
+                addr,
+                len,
+                freed_block,
+            } => {
+                write!(f, "Use after free of size {} at {:#x}", len, addr)?;
+                // the access may start in live memory just below the freed block
+                return freed_block.describe(f, max(*addr, freed_block.addr), "free'd");
+            }
+            AccessError::UseAfterReturn { addr, len, frame } => {
+                write!(f, "Use after return of size {} at {:#x}", len, addr)?;
+                // the access may start in valid memory just below the frame
+                if *addr < frame.addr {
+                    write!(f, ", {} bytes before", frame.addr - addr)?;
+                } else {
+                    write!(f, ", {} bytes inside", addr - frame.addr)?;
+                }
+                return write!(f, " a returned stack frame of size {}", frame.len);
+            }
+            AccessError::HeapBufferOverflow {
+                addr,
+                len,
+                bad_addr,
+                block,
+                ..
+            } => {
+                write!(
+                    f,
+                    "Heap buffer overflow of size {} at {:#x}: byte {:#x} is in a redzone",
+                    len, addr, bad_addr
+                )?;
+                return block.describe(f, *bad_addr, "alloc'd");
+            }
+            AccessError::QuarantinedMalloc {
+                addr,
+                len,
+                freed_block,
+            } => {
+                write!(
+                    f,
+                    "Allocation of size {} at {:#x} reuses quarantined memory",
+                    len, addr
+                )?;
+                return freed_block.describe(f, *addr, "free'd");
+            }
+            AccessError::OutOfBounds { addr, len } => {
+                return write!(
+                    f,
+                    "Access of size {} at {:#x} is outside linear memory",
+                    len, addr
+                );
+            }
+            AccessError::UninitializedUse {
+                point,
+                addr,
+                len,
+                bad_addr,
+                block,
+                ..
+            } => {
+                let what = match point {
+                    UsePoint::HostCall => "read by a host call",
+                    UsePoint::HostBuffer => "copied to a host buffer",
+                };
+                write!(
+                    f,
+                    "Uninitialized byte {:#x} of {} bytes at {:#x} {}",
+                    bad_addr, len, addr, what
+                )?;
+                (*bad_addr, block)
+            }
+            AccessError::UndefinedValue { point } => {
+                let what = match point {
+                    ValueUse::Branch => "Conditional jump or move",
+                    ValueUse::IndirectCall => "Indirect call index",
+                    ValueUse::Address => "Memory address",
+                };
+                return write!(f, "{} depends on uninitialised value(s)", what);
+            }
+            AccessError::CallocOverflow { nmemb, size } => {
+                return write!(
+                    f,
+                    "calloc({}, {}) overflows the allocation size",
+                    nmemb, size
+                );
+            }
+            AccessError::InvalidGrow {
+                cur_pages,
+                delta_pages,
+                max_pages,
+            } => {
+                return write!(
+                    f,
+                    "memory.grow by {} pages from {} pages exceeds the maximum of {} pages",
+                    delta_pages, cur_pages, max_pages
+                );
+            }
+        };
+        match block {
+            Some(block) => block.describe(f, addr, "alloc'd"),
+            None => write!(f, ", not inside or near any allocated block"),
+        }
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+#[test]
+fn display_inside_block() {
+    let mut valgrind_state = crate::Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    let err = valgrind_state.read(0x1000, 4).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Invalid read of size 4 at 0x1000: byte 0x1000 is uninitialized, 0 bytes inside a block of size 32 alloc'd"
+    );
+}
+
+#[test]
+fn display_nearest_block() {
+    let mut valgrind_state = crate::Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.malloc(0x1040, 16).is_ok());
+    assert_eq!(
+        valgrind_state.write(0x1024, 4).unwrap_err().to_string(),
+        "Invalid write of size 4 at 0x1024: byte 0x1024 is unallocated, 4 bytes after a block of size 32 alloc'd"
+    );
+    assert_eq!(
+        valgrind_state.write(0x103c, 4).unwrap_err().to_string(),
+        "Invalid write of size 4 at 0x103c: byte 0x103c is unallocated, 4 bytes before a block of size 16 alloc'd"
+    );
+    assert_eq!(
+        valgrind_state.free(0x1004).unwrap_err().to_string(),
+        "Invalid free() at 0x1004, 4 bytes inside a block of size 32 alloc'd"
+    );
+}
+
+#[test]
+fn display_without_block() {
+    let mut valgrind_state = crate::Valgrind::new(640 * 1024, 0);
+
+    assert_eq!(
+        valgrind_state.read(0x1000, 8).unwrap_err().to_string(),
+        "Invalid read of size 8 at 0x1000: byte 0x1000 is unallocated, not inside or near any allocated block"
+    );
+    assert_eq!(
+        valgrind_state.read(640 * 1024, 1).unwrap_err().to_string(),
+        "Access of size 1 at 0xa0000 is outside linear memory"
+    );
+}
+
+#[test]
+fn error_trait_object() {
+    let mut valgrind_state = crate::Valgrind::new(640 * 1024, 0);
+
+    let err: Box<dyn std::error::Error> = valgrind_state.free(0x1000).unwrap_err().into();
+    assert_eq!(
+        err.to_string(),
+        "Invalid free() at 0x1000, not inside or near any allocated block"
+    );
+}
+
+#[test]
+fn first_bad_byte_of_overflow() {
+    let mut valgrind_state = crate::Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.write(0x1000, 32).is_ok());
+    let err = valgrind_state.read(0x1000, 64).unwrap_err();
+    assert_eq!(
+        err,
+        AccessError::InvalidRead {
+            addr: 0x1000,
+            len: 64,
+            bad_addr: 0x1020,
+            state: MemState::Unallocated,
+            block: Some(Block::new(0x1000, 32)),
+            origin: None,
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "Invalid read of size 64 at 0x1000: byte 0x1020 is unallocated, 0 bytes after a block of size 32 alloc'd"
+    );
+    assert_eq!(
+        Block::new(0x1000, 32).position(0x1020),
+        BlockPosition::After { distance: 0 }
+    );
+}
+
+#[test]
+fn first_uninitialized_byte() {
+    let mut valgrind_state = crate::Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0x1000, 64).is_ok());
+    assert!(valgrind_state.write(0x1000, 16).is_ok());
+    assert!(valgrind_state.write(0x1014, 44).is_ok());
+    assert_eq!(
+        valgrind_state.read(0x1000, 64),
+        Err(AccessError::InvalidRead {
+            addr: 0x1000,
+            len: 64,
+            bad_addr: 0x1010,
+            state: MemState::ValidToWrite,
+            block: Some(Block::new(0x1000, 64)),
+            origin: None,
+        })
+    );
+    assert_eq!(
+        Block::new(0x1000, 64).position(0x1010),
+        BlockPosition::Inside { offset: 16 }
+    );
+}
+
+#[test]
+fn display_use_after_free() {
+    let mut valgrind_state = crate::Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.free(0x1000).is_ok());
+    assert_eq!(
+        valgrind_state.read(0x1008, 4).unwrap_err().to_string(),
+        "Use after free of size 4 at 0x1008, 8 bytes inside a block of size 32 free'd"
+    );
+}
+
+#[test]
+fn display_use_after_free_straddling() {
+    let mut valgrind_state = crate::Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0xff0, 16).is_ok());
+    assert!(valgrind_state.write(0xff0, 16).is_ok());
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.free(0x1000).is_ok());
+    assert_eq!(
+        valgrind_state.read(0xffc, 8).unwrap_err().to_string(),
+        "Use after free of size 8 at 0xffc, 0 bytes inside a block of size 32 free'd"
+    );
+}
+
+#[test]
+fn display_heap_buffer_overflow() {
+    let mut valgrind_state = crate::Valgrind::new(640 * 1024, 0);
+    valgrind_state.set_redzones(4, 4);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert_eq!(
+        valgrind_state.write(0x101e, 4).unwrap_err().to_string(),
+        "Heap buffer overflow of size 4 at 0x101e: byte 0x1020 is in a redzone, 0 bytes after a block of size 32 alloc'd"
+    );
+}
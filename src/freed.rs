@@ -0,0 +1,120 @@
+/*
+Freed ranges remember which block each `MemState::Freed` byte originally belonged
+to. The ranges are disjoint: a block can only be freed while its bytes are
+allocated, and allocating over freed bytes trims or splits the ranges covering
+them.
+
+When a quarantine is configured, freed ranges additionally queue up in FIFO
+order until their total size exceeds the byte budget. Quarantined ranges may not
+be reallocated; once evicted they revert to plain unallocated memory.
+*/
+
+use crate::Block;
+use std::collections::{BTreeMap, VecDeque};
+
+#[derive(Debug, Default)]
+pub(crate) struct FreedRanges {
+    ranges: BTreeMap<usize, (usize, Block)>, // start addr -> (end addr, freed block)
+}
+
+impl FreedRanges {
+    pub(crate) fn insert(&mut self, addr: usize, len: usize, block: Block) {
+        if len > 0 {
+            self.ranges.insert(addr, (addr + len, block));
+        }
+    }
+    /// Forgets the bytes in `addr..addr + len`, e.g. because they were allocated again.
+    pub(crate) fn remove(&mut self, addr: usize, len: usize) {
+        let end = addr + len;
+        let overlapping: Vec<usize> = self
+            .ranges
+            .range(..end)
+            .rev()
+            .take_while(|(_, (range_end, _))| *range_end > addr)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in overlapping {
+            let (range_end, block) = self.ranges.remove(&start).unwrap();
+            if start < addr {
+                self.ranges.insert(start, (addr, block.clone()));
+            }
+            if range_end > end {
+                self.ranges.insert(end, (range_end, block));
+            }
+        }
+    }
+    /// Returns the block of the first freed range overlapping `addr..addr + len`.
+    pub(crate) fn overlapping(&self, addr: usize, len: usize) -> Option<&Block> {
+        if let Some(block) = self.block_at(addr) {
+            return Some(block);
+        }
+        let (_, (_, block)) = self.ranges.range(addr..addr + len).next()?;
+        Some(block)
+    }
+    pub(crate) fn block_at(&self, addr: usize) -> Option<&Block> {
+        let (_, (end, block)) = self.ranges.range(..=addr).next_back()?;
+        if addr < *end {
+            Some(block)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Quarantine {
+    budget: usize,
+    bytes: usize,
+    queue: VecDeque<(usize, usize)>, // addr, len; oldest first
+}
+
+impl Quarantine {
+    pub(crate) fn new(budget: usize) -> Quarantine {
+        Quarantine {
+            budget,
+            bytes: 0,
+            queue: VecDeque::new(),
+        }
+    }
+    pub(crate) fn push(&mut self, addr: usize, len: usize) {
+        if len > 0 {
+            self.queue.push_back((addr, len));
+            self.bytes += len;
+        }
+    }
+    /// Pops the oldest range while the quarantine holds more than its budget.
+    pub(crate) fn evict(&mut self) -> Option<(usize, usize)> {
+        if self.bytes <= self.budget {
+            return None;
+        }
+        let (addr, len) = self.queue.pop_front()?;
+        self.bytes -= len;
+        Some((addr, len))
+    }
+}
+
+#[test]
+fn quarantine_evicts_oldest_first() {
+    let mut quarantine = Quarantine::new(48);
+
+    quarantine.push(0x1000, 32);
+    quarantine.push(0x2000, 16);
+    assert_eq!(quarantine.evict(), None);
+    quarantine.push(0x3000, 8);
+    assert_eq!(quarantine.evict(), Some((0x1000, 32)));
+    assert_eq!(quarantine.evict(), None);
+}
+
+#[test]
+fn remove_splits_ranges() {
+    let mut freed = FreedRanges::default();
+
+    freed.insert(0x1000, 0x40, Block::new(0x1000, 0x40));
+    freed.insert(0x1040, 0x10, Block::new(0x1040, 0x10));
+    freed.remove(0x1010, 0x38);
+    assert_eq!(freed.block_at(0x100f), Some(&Block::new(0x1000, 0x40)));
+    assert_eq!(freed.block_at(0x1010), None);
+    assert_eq!(freed.block_at(0x1047), None);
+    assert_eq!(freed.block_at(0x1048), Some(&Block::new(0x1040, 0x10)));
+    assert_eq!(freed.block_at(0x1050), None);
+}
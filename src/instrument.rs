@@ -0,0 +1,1847 @@
+/*
+Rewrites a wasm module so that every access to linear memory first calls an
+imported hook with the access's base address, static offset and width, and the
+instruction's offset in the original module as its site. The hooks are appended to
+the import section, which shifts the index of every function defined in the module;
+all references to those functions are remapped.
+
+Allocator functions found by name can additionally be wrapped: the wrappers are
+appended to the module and every call, export and table entry of the original is
+redirected to them. A wrapper brackets the original call with `alloc_enter` and
+`alloc_exit` and reports its arguments and result to a per-function hook; `free`
+is reported before the block is released, everything else once it returns.
+
+Every `global.set` of the stack pointer global (`__stack_pointer` for LLVM output)
+is preceded by a call reporting the new value, so the host can follow stack frames.
+
+Optionally every function reports being entered and exited, so the host can keep a
+shadow call stack. The body is wrapped in a block of the function's result type whose
+end calls `exit`: a branch to the function's label now lands there, and the relative
+depths of all branches stay the same. `return` and tail calls call `exit` themselves.
+
+With moves enabled, a load whose value is immediately stored with the same width, as
+in copies of structs and their padding, is reported by a single `move_value` call
+instead, so the host can carry the definedness of the bytes along.
+
+Bulk memory operations on memory 0 are reported by their own hooks, imported only by
+modules that use them: `memory.copy` as a copy between two ranges, and `memory.fill` and
+`memory.init` as a fill of their destination, since passive data is always initialized.
+`data.drop` does not touch memory.
+
+With value shadows enabled, the definedness of every wasm value is tracked alongside it
+(see `values`): loads and stores of values report through `load_value` and `store_value`
+instead, and undefined values used as conditions, table indices or addresses are
+reported to `undefined_value`. Shadows pass through calls in globals appended to the
+module's own.
+
+With global roots enabled, the i32 and i64 globals a module defines but does not export
+are exported under `GLOBAL_EXPORT_PREFIX`, so the host can read pointers kept in them at
+leak check time.
+
+Only memory 0 of a 32-bit memory is instrumented. Atomic operations and copies between
+memories are left untouched.
+*/
+
+mod values;
+
+#[cfg(test)]
+use crate::ValueUse;
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt;
+use std::ops::Range;
+use values::{Linkage, ValueShadows};
+use wasm_encoder::reencode::{self, Reencode};
+use wasm_encoder::{Function, Instruction, SectionId, ValType};
+use wasmparser::{
+    ExternalKind, FuncToValidate, KnownCustom, Name, Operator, Parser, Payload, TypeRef,
+    ValidatorResources,
+};
+
+/// Module name of the imported hooks.
+pub const HOOK_MODULE: &str = "wasm_valgrind";
+/// `(addr: i32, offset: i32, len: i32, site: i32)`, called before every load.
+pub const LOAD_HOOK: &str = "load";
+/// `(addr: i32, offset: i32, len: i32, site: i32)`, called before every store.
+pub const STORE_HOOK: &str = "store";
+/// `(dst: i32, src: i32, len: i32, site: i32)`, called before every `memory.copy`.
+pub const COPY_HOOK: &str = "copy";
+/// `(dst: i32, len: i32, site: i32)`, called before every `memory.fill` and `memory.init`.
+pub const FILL_HOOK: &str = "fill";
+/// `(dst: i32, dst_offset: i32, src: i32, src_offset: i32, len: i32, site: i32)`, called
+/// before a load whose value is immediately stored. The site is the load's.
+pub const MOVE_HOOK: &str = "move_value";
+/// `(addr: i32, offset: i32, len: i32, site: i32) -> i32`, called before every load when
+/// tracking value definedness; returns nonzero if the loaded value is undefined.
+pub const LOAD_VALUE_HOOK: &str = "load_value";
+/// `(addr: i32, offset: i32, len: i32, undefined: i32, site: i32)`, called before every
+/// store when tracking value definedness.
+pub const STORE_VALUE_HOOK: &str = "store_value";
+/// `(point: i32, site: i32)`, called when an undefined value reaches a use point, given
+/// as a `ValueUse::code`.
+pub const UNDEFINED_VALUE_HOOK: &str = "undefined_value";
+/// `()`, called when a wrapped allocator function is entered.
+pub const ALLOC_ENTER_HOOK: &str = "alloc_enter";
+/// `()`, called when a wrapped allocator function returns.
+pub const ALLOC_EXIT_HOOK: &str = "alloc_exit";
+/// `(ret: i32, size: i32)`, called after `malloc` returns.
+pub const MALLOC_HOOK: &str = "malloc";
+/// `(ptr: i32)`, called before `free` runs.
+pub const FREE_HOOK: &str = "free";
+/// `(ret: i32, nmemb: i32, size: i32)`, called after `calloc` returns.
+pub const CALLOC_HOOK: &str = "calloc";
+/// `(ret: i32, ptr: i32, size: i32)`, called after `realloc` returns.
+pub const REALLOC_HOOK: &str = "realloc";
+/// `(ret: i32, align: i32, size: i32)`, called after `aligned_alloc` returns.
+pub const ALIGNED_ALLOC_HOOK: &str = "aligned_alloc";
+/// `(new_sp: i32)`, called before every write of the stack pointer global.
+pub const STACK_POINTER_HOOK: &str = "stack_pointer";
+/// `(func: i32)`, called on entry to a function with its index in the original module.
+pub const ENTER_HOOK: &str = "enter";
+/// `()`, called when a function returns.
+pub const EXIT_HOOK: &str = "exit";
+/// Prefix of the exports added by `Config::set_global_roots`, followed by the global's
+/// index.
+pub const GLOBAL_EXPORT_PREFIX: &str = "wasm_valgrind.global.";
+
+#[derive(Debug)]
+pub enum InstrumentError {
+    Parse(wasmparser::BinaryReaderError),
+    Reencode(reencode::Error),
+    Unsupported(&'static str),
+    AllocatorSignature { name: String },
+}
+
+impl fmt::Display for InstrumentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstrumentError::Parse(err) => write!(f, "failed to parse module: {}", err),
+            InstrumentError::Reencode(err) => write!(f, "failed to rewrite module: {}", err),
+            InstrumentError::Unsupported(what) => write!(f, "unsupported module: {}", what),
+            InstrumentError::AllocatorSignature { name } => {
+                write!(
+                    f,
+                    "allocator function `{}` has an unexpected signature",
+                    name
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for InstrumentError {}
+
+impl From<wasmparser::BinaryReaderError> for InstrumentError {
+    fn from(err: wasmparser::BinaryReaderError) -> Self {
+        InstrumentError::Parse(err)
+    }
+}
+
+impl From<reencode::Error> for InstrumentError {
+    fn from(err: reencode::Error) -> Self {
+        InstrumentError::Reencode(err)
+    }
+}
+
+/// Export (or, failing that, `name` section) names of the allocator functions to wrap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllocatorNames {
+    pub malloc: String,
+    pub free: String,
+    pub calloc: String,
+    pub realloc: String,
+    pub aligned_alloc: String,
+}
+
+impl Default for AllocatorNames {
+    fn default() -> Self {
+        AllocatorNames {
+            malloc: "malloc".to_string(),
+            free: "free".to_string(),
+            calloc: "calloc".to_string(),
+            realloc: "realloc".to_string(),
+            aligned_alloc: "aligned_alloc".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    allocator: Option<AllocatorNames>,
+    stack_pointer: Option<String>,
+    call_stacks: bool,
+    moves: bool,
+    value_shadows: bool,
+    global_roots: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            allocator: None,
+            stack_pointer: Some("__stack_pointer".to_string()),
+            call_stacks: false,
+            moves: false,
+            value_shadows: false,
+            global_roots: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config::default()
+    }
+    /// Name of the mutable i32 global holding the stack pointer (default: `__stack_pointer`),
+    /// looked up among exports, imports and the `name` section. `None` disables tracking.
+    pub fn set_stack_pointer(&mut self, name: Option<&str>) -> &mut Config {
+        self.stack_pointer = name.map(str::to_string);
+        self
+    }
+    /// Wraps the allocator functions called `names` so that the host sees every allocation.
+    /// Functions that cannot be found are left alone.
+    pub fn set_allocator(&mut self, names: AllocatorNames) -> &mut Config {
+        self.allocator = Some(names);
+        self
+    }
+    /// Reports every function entry and exit, so that errors and blocks can carry call
+    /// stacks. Off by default.
+    pub fn set_call_stacks(&mut self, enabled: bool) -> &mut Config {
+        self.call_stacks = enabled;
+        self
+    }
+    /// Reports loads whose value is immediately stored as moves, for checking uninitialized
+    /// bytes where they are used rather than where they are loaded. Off by default.
+    pub fn set_moves(&mut self, enabled: bool) -> &mut Config {
+        self.moves = enabled;
+        self
+    }
+    /// Tracks whether each wasm value is defined, reporting undefined values used as
+    /// conditions, table indices or addresses. Checked by `UndefinedChecks::OnUse`;
+    /// off by default.
+    pub fn set_value_shadows(&mut self, enabled: bool) -> &mut Config {
+        self.value_shadows = enabled;
+        self
+    }
+    /// Exports the i32 and i64 globals the module defines but does not export, so that
+    /// the host can scan them as leak roots. Off by default.
+    pub fn set_global_roots(&mut self, enabled: bool) -> &mut Config {
+        self.global_roots = enabled;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Hook {
+    Load,
+    Store,
+    Copy,
+    Fill,
+    Move,
+    LoadValue,
+    StoreValue,
+    UndefinedValue,
+    AllocEnter,
+    AllocExit,
+    Malloc,
+    Free,
+    Calloc,
+    Realloc,
+    AlignedAlloc,
+    StackPointer,
+    Enter,
+    Exit,
+}
+
+impl Hook {
+    fn name(self) -> &'static str {
+        match self {
+            Hook::Load => LOAD_HOOK,
+            Hook::Store => STORE_HOOK,
+            Hook::Copy => COPY_HOOK,
+            Hook::Fill => FILL_HOOK,
+            Hook::Move => MOVE_HOOK,
+            Hook::LoadValue => LOAD_VALUE_HOOK,
+            Hook::StoreValue => STORE_VALUE_HOOK,
+            Hook::UndefinedValue => UNDEFINED_VALUE_HOOK,
+            Hook::AllocEnter => ALLOC_ENTER_HOOK,
+            Hook::AllocExit => ALLOC_EXIT_HOOK,
+            Hook::Malloc => MALLOC_HOOK,
+            Hook::Free => FREE_HOOK,
+            Hook::Calloc => CALLOC_HOOK,
+            Hook::Realloc => REALLOC_HOOK,
+            Hook::AlignedAlloc => ALIGNED_ALLOC_HOOK,
+            Hook::StackPointer => STACK_POINTER_HOOK,
+            Hook::Enter => ENTER_HOOK,
+            Hook::Exit => EXIT_HOOK,
+        }
+    }
+    fn params(self) -> &'static [ValType] {
+        match self {
+            Hook::AllocEnter | Hook::AllocExit | Hook::Exit => &[],
+            Hook::Free | Hook::StackPointer | Hook::Enter => &[ValType::I32],
+            Hook::Malloc | Hook::UndefinedValue => &[ValType::I32, ValType::I32],
+            Hook::Calloc | Hook::Realloc | Hook::AlignedAlloc | Hook::Fill => {
+                &[ValType::I32, ValType::I32, ValType::I32]
+            }
+            Hook::Load | Hook::Store | Hook::Copy | Hook::LoadValue => {
+                &[ValType::I32, ValType::I32, ValType::I32, ValType::I32]
+            }
+            Hook::StoreValue => &[ValType::I32; 5],
+            Hook::Move => &[ValType::I32; 6],
+        }
+    }
+    fn results(self) -> &'static [ValType] {
+        match self {
+            Hook::LoadValue => &[ValType::I32],
+            _ => &[],
+        }
+    }
+    /// Parameter and result counts of the allocator function reported by this hook.
+    fn wrapped_signature(self) -> (usize, usize) {
+        match self {
+            Hook::Malloc => (1, 1),
+            Hook::Free => (1, 0),
+            _ => (2, 1),
+        }
+    }
+}
+
+/// What the rewrite needs to know about the input module before emitting anything.
+#[derive(Default)]
+struct ModuleInfo {
+    types: Vec<Option<wasmparser::FuncType>>,
+    imported_funcs: u32,
+    funcs: Vec<u32>, // type index per function, imports first
+    exports: HashMap<String, u32>,
+    names: HashMap<String, u32>,
+    func_names: HashMap<u32, String>, // from the name section
+    bodies: Vec<Range<usize>>,        // code of each defined function
+    imported_globals: u32,
+    globals: Vec<wasmparser::GlobalType>, // imports first
+    global_inits: Vec<Option<u32>>,
+    global_names: HashMap<String, u32>,
+    exported_globals: Vec<u32>,
+    data: Vec<Range<u32>>, // active segments of memory 0
+    bulk_memory: bool,     // whether any function copies, fills or initializes memory 0
+}
+
+impl ModuleInfo {
+    fn parse(wasm: &[u8]) -> Result<ModuleInfo, InstrumentError> {
+        let mut info = ModuleInfo::default();
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload? {
+                Payload::Version {
+                    encoding: wasmparser::Encoding::Component,
+                    ..
+                } => return Err(InstrumentError::Unsupported("components")),
+                Payload::TypeSection(section) => {
+                    for rec_group in section {
+                        for sub_type in rec_group?.into_types() {
+                            info.types.push(match sub_type.composite_type.inner {
+                                wasmparser::CompositeInnerType::Func(ty) => Some(ty),
+                                _ => None,
+                            });
+                        }
+                    }
+                }
+                Payload::ImportSection(section) => {
+                    for import in section.into_imports() {
+                        let import = import?;
+                        match import.ty {
+                            TypeRef::Func(ty) | TypeRef::FuncExact(ty) => {
+                                info.imported_funcs += 1;
+                                info.funcs.push(ty);
+                            }
+                            TypeRef::Global(ty) => {
+                                let index = info.globals.len() as u32;
+                                info.global_names.insert(import.name.to_string(), index);
+                                info.imported_globals += 1;
+                                info.globals.push(ty);
+                                info.global_inits.push(None);
+                            }
+                            TypeRef::Memory(ty) if ty.memory64 => {
+                                return Err(InstrumentError::Unsupported("64-bit memories"))
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::FunctionSection(section) => {
+                    for ty in section {
+                        info.funcs.push(ty?);
+                    }
+                }
+                Payload::GlobalSection(section) => {
+                    for global in section {
+                        let global = global?;
+                        info.globals.push(global.ty);
+                        info.global_inits.push(const_i32(&global.init_expr));
+                    }
+                }
+                Payload::MemorySection(section) => {
+                    for memory in section {
+                        if memory?.memory64 {
+                            return Err(InstrumentError::Unsupported("64-bit memories"));
+                        }
+                    }
+                }
+                Payload::DataSection(section) => {
+                    for data in section {
+                        let data = data?;
+                        if let wasmparser::DataKind::Active {
+                            memory_index: 0,
+                            offset_expr,
+                        } = data.kind
+                        {
+                            if let Some(offset) = const_i32(&offset_expr) {
+                                // a segment past 4 GiB fails to instantiate anyway
+                                let len = u32::try_from(data.data.len()).unwrap_or(u32::MAX);
+                                info.data.push(offset..offset.saturating_add(len));
+                            }
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    info.bodies.push(body.range());
+                    let mut reader = body.get_operators_reader()?;
+                    while !info.bulk_memory && !reader.eof() {
+                        info.bulk_memory = bulk_access(&reader.read()?).is_some();
+                    }
+                }
+                Payload::ExportSection(section) => {
+                    for export in section {
+                        let export = export?;
+                        match export.kind {
+                            ExternalKind::Func => {
+                                info.exports.insert(export.name.to_string(), export.index);
+                            }
+                            ExternalKind::Global => {
+                                info.global_names
+                                    .insert(export.name.to_string(), export.index);
+                                info.exported_globals.push(export.index);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::CustomSection(section) => {
+                    if let KnownCustom::Name(names) = section.as_known() {
+                        // a malformed name section is ignored, as engines do
+                        for name in names.flatten() {
+                            let (map, names, is_func) = match name {
+                                Name::Function(map) => (map, &mut info.names, true),
+                                Name::Global(map) => (map, &mut info.global_names, false),
+                                _ => continue,
+                            };
+                            for naming in map.into_iter().flatten() {
+                                names.entry(naming.name.to_string()).or_insert(naming.index);
+                                if is_func {
+                                    info.func_names
+                                        .insert(naming.index, naming.name.to_string());
+                                }
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(info)
+    }
+    fn func_type(&self, func: u32) -> Option<&wasmparser::FuncType> {
+        let ty = *self.funcs.get(func as usize)?;
+        self.types.get(ty as usize)?.as_ref()
+    }
+    fn find_func(&self, name: &str) -> Option<u32> {
+        self.exports
+            .get(name)
+            .or_else(|| self.names.get(name))
+            .copied()
+    }
+    fn global_init(&self, name: &str) -> Option<u32> {
+        let index = *self.global_names.get(name)?;
+        *self.global_inits.get(index as usize)?
+    }
+    /// Index of the mutable i32 global called `name`.
+    fn find_stack_pointer(&self, name: &str) -> Option<u32> {
+        let index = *self.global_names.get(name)?;
+        let ty = self.globals.get(index as usize)?;
+        (ty.mutable && ty.content_type == wasmparser::ValType::I32).then_some(index)
+    }
+}
+
+/// Scratch locals appended to every instrumented function.
+struct Scratch {
+    addr: u32,
+    i32: u32,
+    len: u32,
+    i64: u32,
+    f32: u32,
+    f64: u32,
+    v128: u32,
+}
+
+impl Scratch {
+    fn value(&self, ty: ValType) -> u32 {
+        match ty {
+            ValType::I32 => self.i32,
+            ValType::I64 => self.i64,
+            ValType::F32 => self.f32,
+            ValType::F64 => self.f64,
+            _ => self.v128,
+        }
+    }
+}
+
+/// How an instruction touches memory.
+struct Access {
+    hook: Hook,
+    memarg: wasmparser::MemArg,
+    len: u32,
+    value: Option<ValType>, // operand above the address on the stack, if any
+}
+
+fn memory_access(op: &Operator) -> Option<Access> {
+    use Operator::*;
+    let load = |memarg: &wasmparser::MemArg, len| Access {
+        hook: Hook::Load,
+        memarg: *memarg,
+        len,
+        value: None,
+    };
+    let store = |memarg: &wasmparser::MemArg, len, value| Access {
+        hook: Hook::Store,
+        memarg: *memarg,
+        len,
+        value: Some(value),
+    };
+    let access = match op {
+        I32Load8S { memarg } | I32Load8U { memarg } => load(memarg, 1),
+        I64Load8S { memarg } | I64Load8U { memarg } => load(memarg, 1),
+        I32Load16S { memarg } | I32Load16U { memarg } => load(memarg, 2),
+        I64Load16S { memarg } | I64Load16U { memarg } => load(memarg, 2),
+        I32Load { memarg } | F32Load { memarg } => load(memarg, 4),
+        I64Load32S { memarg } | I64Load32U { memarg } => load(memarg, 4),
+        I64Load { memarg } | F64Load { memarg } => load(memarg, 8),
+        V128Load { memarg } => load(memarg, 16),
+        V128Load8x8S { memarg } | V128Load8x8U { memarg } => load(memarg, 8),
+        V128Load16x4S { memarg } | V128Load16x4U { memarg } => load(memarg, 8),
+        V128Load32x2S { memarg } | V128Load32x2U { memarg } => load(memarg, 8),
+        V128Load8Splat { memarg } => load(memarg, 1),
+        V128Load16Splat { memarg } => load(memarg, 2),
+        V128Load32Splat { memarg } | V128Load32Zero { memarg } => load(memarg, 4),
+        V128Load64Splat { memarg } | V128Load64Zero { memarg } => load(memarg, 8),
+        V128Load8Lane { memarg, .. } => Access {
+            value: Some(ValType::V128),
+            ..load(memarg, 1)
+        },
+        V128Load16Lane { memarg, .. } => Access {
+            value: Some(ValType::V128),
+            ..load(memarg, 2)
+        },
+        V128Load32Lane { memarg, .. } => Access {
+            value: Some(ValType::V128),
+            ..load(memarg, 4)
+        },
+        V128Load64Lane { memarg, .. } => Access {
+            value: Some(ValType::V128),
+            ..load(memarg, 8)
+        },
+        I32Store8 { memarg } => store(memarg, 1, ValType::I32),
+        I64Store8 { memarg } => store(memarg, 1, ValType::I64),
+        I32Store16 { memarg } => store(memarg, 2, ValType::I32),
+        I64Store16 { memarg } => store(memarg, 2, ValType::I64),
+        I32Store { memarg } => store(memarg, 4, ValType::I32),
+        I64Store32 { memarg } => store(memarg, 4, ValType::I64),
+        F32Store { memarg } => store(memarg, 4, ValType::F32),
+        I64Store { memarg } => store(memarg, 8, ValType::I64),
+        F64Store { memarg } => store(memarg, 8, ValType::F64),
+        V128Store { memarg } => store(memarg, 16, ValType::V128),
+        V128Store8Lane { memarg, .. } => store(memarg, 1, ValType::V128),
+        V128Store16Lane { memarg, .. } => store(memarg, 2, ValType::V128),
+        V128Store32Lane { memarg, .. } => store(memarg, 4, ValType::V128),
+        V128Store64Lane { memarg, .. } => store(memarg, 8, ValType::V128),
+        _ => return None,
+    };
+    if access.memarg.memory == 0 {
+        Some(access)
+    } else {
+        None
+    }
+}
+
+/// The hook of a bulk memory operation on memory 0.
+fn bulk_access(op: &Operator) -> Option<Hook> {
+    match op {
+        Operator::MemoryCopy {
+            dst_mem: 0,
+            src_mem: 0,
+        } => Some(Hook::Copy),
+        // init always defines its target; `data.drop` needs no model, as init from a
+        // dropped segment traps before writing anything
+        Operator::MemoryFill { mem: 0 } | Operator::MemoryInit { mem: 0, .. } => Some(Hook::Fill),
+        _ => None,
+    }
+}
+
+/// An allocator function and the hook its wrapper reports to.
+struct Wrapper {
+    hook: Hook,
+    func: u32, // index in the original module
+    name: String,
+}
+
+struct Instrumenter<'a> {
+    info: &'a ModuleInfo,
+    hooks: Vec<Hook>,
+    wrappers: Vec<Wrapper>,
+    stack_pointer: Option<u32>,
+    call_stacks: bool,
+    moves: bool,
+    global_roots: bool,
+    shadow_globals: u32, // appended to the module's globals, for value shadows of calls
+    validators: VecDeque<FuncToValidate<ValidatorResources>>, // per body, for value shadows
+    block_types: Vec<Vec<ValType>>, // multi-value results of wrapped bodies
+    emitted: Vec<SectionId>,
+    next_func: usize,
+}
+
+/// Position of a section in the order mandated by the spec.
+fn section_rank(id: SectionId) -> u32 {
+    match id {
+        SectionId::Custom => 0,
+        SectionId::Type => 1,
+        SectionId::Import => 2,
+        SectionId::Function => 3,
+        SectionId::Table => 4,
+        SectionId::Memory => 5,
+        SectionId::Tag => 6,
+        SectionId::Global => 7,
+        SectionId::Export => 8,
+        SectionId::Start => 9,
+        SectionId::Element => 10,
+        SectionId::DataCount => 11,
+        SectionId::Code => 12,
+        SectionId::Data => 13,
+    }
+}
+
+impl Instrumenter<'_> {
+    fn new<'a>(info: &'a ModuleInfo, config: &Config) -> Result<Instrumenter<'a>, InstrumentError> {
+        let mut hooks = vec![Hook::Load, Hook::Store];
+        if info.bulk_memory {
+            hooks.extend([Hook::Copy, Hook::Fill]);
+        }
+        let mut wrappers = Vec::new();
+        if let Some(names) = &config.allocator {
+            let candidates = [
+                (Hook::Malloc, &names.malloc),
+                (Hook::Free, &names.free),
+                (Hook::Calloc, &names.calloc),
+                (Hook::Realloc, &names.realloc),
+                (Hook::AlignedAlloc, &names.aligned_alloc),
+            ];
+            for (hook, name) in candidates {
+                let Some(func) = info.find_func(name) else {
+                    continue;
+                };
+                let (params, results) = hook.wrapped_signature();
+                let i32s = |types: &[wasmparser::ValType], n| {
+                    types.len() == n && types.iter().all(|ty| *ty == wasmparser::ValType::I32)
+                };
+                match info.func_type(func) {
+                    Some(ty) if i32s(ty.params(), params) && i32s(ty.results(), results) => {}
+                    _ => return Err(InstrumentError::AllocatorSignature { name: name.clone() }),
+                }
+                hooks.push(hook);
+                wrappers.push(Wrapper {
+                    hook,
+                    func,
+                    name: name.clone(),
+                });
+            }
+            if !wrappers.is_empty() {
+                hooks.extend([Hook::AllocEnter, Hook::AllocExit]);
+            }
+        }
+        let stack_pointer = config
+            .stack_pointer
+            .as_deref()
+            .and_then(|name| info.find_stack_pointer(name));
+        if stack_pointer.is_some() {
+            hooks.push(Hook::StackPointer);
+        }
+        let mut block_types = Vec::new();
+        let mut shadow_globals = 0;
+        if config.moves {
+            hooks.push(Hook::Move);
+        }
+        if config.value_shadows {
+            hooks.extend([Hook::LoadValue, Hook::StoreValue, Hook::UndefinedValue]);
+            let arity = info
+                .types
+                .iter()
+                .flatten()
+                .map(|ty| std::cmp::max(ty.params().len(), ty.results().len()) as u32);
+            shadow_globals = Linkage::globals(arity.max().unwrap_or(0));
+        }
+        if config.call_stacks {
+            hooks.extend([Hook::Enter, Hook::Exit]);
+            for func in info.imported_funcs..info.funcs.len() as u32 {
+                let results = info.func_type(func).map_or(&[][..], |ty| ty.results());
+                let results = results
+                    .iter()
+                    .map(|ty| ValType::try_from(*ty))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if results.len() > 1 && !block_types.contains(&results) {
+                    block_types.push(results);
+                }
+            }
+        }
+        Ok(Instrumenter {
+            info,
+            hooks,
+            wrappers,
+            stack_pointer,
+            call_stacks: config.call_stacks,
+            moves: config.moves,
+            global_roots: config.global_roots,
+            shadow_globals,
+            validators: VecDeque::new(),
+            block_types,
+            emitted: Vec::new(),
+            next_func: 0,
+        })
+    }
+    fn hook_type(&self, hook: Hook) -> u32 {
+        let index = self.hooks.iter().position(|h| *h == hook).unwrap();
+        self.info.types.len() as u32 + index as u32
+    }
+    fn hook_func(&self, hook: Hook) -> u32 {
+        let index = self.hooks.iter().position(|h| *h == hook).unwrap();
+        self.info.imported_funcs + index as u32
+    }
+    /// Index of `func` in the rewritten module, without redirecting it to a wrapper.
+    fn shifted(&self, func: u32) -> u32 {
+        if func < self.info.imported_funcs {
+            func
+        } else {
+            func + self.hooks.len() as u32
+        }
+    }
+    fn wrapper_func(&self, index: usize) -> u32 {
+        self.info.funcs.len() as u32 + self.hooks.len() as u32 + index as u32
+    }
+    /// The i32 and i64 globals the module defines and keeps to itself, which may hold
+    /// pointers, if global roots are enabled.
+    fn root_globals(&self) -> impl Iterator<Item = u32> + '_ {
+        let info = self.info;
+        let defined = match self.global_roots {
+            true => info.imported_globals..info.globals.len() as u32,
+            false => 0..0,
+        };
+        defined.filter(move |global| {
+            let ty = info.globals[*global as usize].content_type;
+            matches!(ty, wasmparser::ValType::I32 | wasmparser::ValType::I64)
+                && !info.exported_globals.contains(global)
+        })
+    }
+    /// The globals value shadows pass through calls, each a mutable i32 starting at -1,
+    /// which names no function.
+    fn add_shadow_globals(&self, globals: &mut wasm_encoder::GlobalSection) {
+        for _ in 0..self.shadow_globals {
+            let ty = wasm_encoder::GlobalType {
+                val_type: ValType::I32,
+                mutable: true,
+                shared: false,
+            };
+            globals.global(ty, &wasm_encoder::ConstExpr::i32_const(-1));
+        }
+    }
+    fn add_global_exports(&self, exports: &mut wasm_encoder::ExportSection) {
+        for global in self.root_globals() {
+            let name = format!("{}{}", GLOBAL_EXPORT_PREFIX, global);
+            exports.export(&name, wasm_encoder::ExportKind::Global, global);
+        }
+    }
+    fn add_hook_types(&self, types: &mut wasm_encoder::TypeSection) {
+        for hook in &self.hooks {
+            types.ty().function(
+                hook.params().iter().copied(),
+                hook.results().iter().copied(),
+            );
+        }
+        for results in &self.block_types {
+            types.ty().function([], results.iter().copied());
+        }
+    }
+    /// Type of the block wrapping a body of type `ty`, which yields the function's results.
+    fn body_block_type(&self, ty: u32) -> wasm_encoder::BlockType {
+        let results = self.info.types[ty as usize]
+            .as_ref()
+            .map_or(&[][..], |ty| ty.results());
+        match results {
+            [] => wasm_encoder::BlockType::Empty,
+            [result] => wasm_encoder::BlockType::Result(ValType::try_from(*result).unwrap()),
+            _ => {
+                let results = results.iter().map(|ty| ValType::try_from(*ty).unwrap());
+                let results = results.collect::<Vec<_>>();
+                let index = self.block_types.iter().position(|r| *r == results).unwrap();
+                let base = self.info.types.len() + self.hooks.len();
+                wasm_encoder::BlockType::FunctionType((base + index) as u32)
+            }
+        }
+    }
+    fn add_hook_imports(&self, imports: &mut wasm_encoder::ImportSection) {
+        for hook in &self.hooks {
+            imports.import(
+                HOOK_MODULE,
+                hook.name(),
+                wasm_encoder::EntityType::Function(self.hook_type(*hook)),
+            );
+        }
+    }
+    fn add_wrapper_functions(&self, functions: &mut wasm_encoder::FunctionSection) {
+        for wrapper in &self.wrappers {
+            functions.function(self.info.funcs[wrapper.func as usize]);
+        }
+    }
+    fn add_wrapper_bodies(&self, code: &mut wasm_encoder::CodeSection) {
+        for wrapper in &self.wrappers {
+            let (params, results) = wrapper.hook.wrapped_signature();
+            let params = params as u32;
+            let ret = params; // scratch local holding the result
+            let mut f = Function::new([(results as u32, ValType::I32)]);
+            let call = |f: &mut Function, hook| {
+                f.instruction(&Instruction::Call(self.hook_func(hook)));
+            };
+            if wrapper.hook == Hook::Free {
+                f.instruction(&Instruction::LocalGet(0));
+                call(&mut f, Hook::Free);
+            }
+            call(&mut f, Hook::AllocEnter);
+            for param in 0..params {
+                f.instruction(&Instruction::LocalGet(param));
+            }
+            f.instruction(&Instruction::Call(self.shifted(wrapper.func)));
+            call(&mut f, Hook::AllocExit);
+            if wrapper.hook != Hook::Free {
+                f.instruction(&Instruction::LocalTee(ret));
+                for param in 0..params {
+                    f.instruction(&Instruction::LocalGet(param));
+                }
+                call(&mut f, wrapper.hook);
+                f.instruction(&Instruction::LocalGet(ret));
+            }
+            f.instruction(&Instruction::End);
+            code.function(&f);
+        }
+    }
+    /// With a value `shadow`, loads report to `load_value`, which sets the shadow, and
+    /// stores pass it to `store_value`.
+    fn emit_access(
+        &self,
+        f: &mut Function,
+        scratch: &Scratch,
+        access: &Access,
+        shadow: Option<u32>,
+        site: usize,
+    ) {
+        if let Some(ty) = access.value {
+            f.instruction(&Instruction::LocalSet(scratch.value(ty)));
+        }
+        f.instruction(&Instruction::LocalTee(scratch.addr));
+        f.instruction(&Instruction::I32Const(access.memarg.offset as i32));
+        f.instruction(&Instruction::I32Const(access.len as i32));
+        let hook = match (access.hook, shadow) {
+            (Hook::Load, Some(_)) => Hook::LoadValue,
+            (Hook::Store, Some(shadow)) => {
+                f.instruction(&Instruction::LocalGet(shadow));
+                Hook::StoreValue
+            }
+            (hook, _) => hook,
+        };
+        f.instruction(&Instruction::I32Const(site as i32));
+        f.instruction(&Instruction::Call(self.hook_func(hook)));
+        if let (Hook::LoadValue, Some(shadow)) = (hook, shadow) {
+            f.instruction(&Instruction::LocalSet(shadow));
+        }
+        f.instruction(&Instruction::LocalGet(scratch.addr));
+        if let Some(ty) = access.value {
+            f.instruction(&Instruction::LocalGet(scratch.value(ty)));
+        }
+    }
+    /// Reports a load followed by a store of its value, with the operands `[dst, src]`.
+    fn emit_move(
+        &self,
+        f: &mut Function,
+        scratch: &Scratch,
+        load: &Access,
+        store: &Access,
+        site: usize,
+    ) {
+        f.instruction(&Instruction::LocalSet(scratch.i32));
+        f.instruction(&Instruction::LocalTee(scratch.addr));
+        f.instruction(&Instruction::I32Const(store.memarg.offset as i32));
+        f.instruction(&Instruction::LocalGet(scratch.i32));
+        f.instruction(&Instruction::I32Const(load.memarg.offset as i32));
+        f.instruction(&Instruction::I32Const(load.len as i32));
+        f.instruction(&Instruction::I32Const(site as i32));
+        f.instruction(&Instruction::Call(self.hook_func(Hook::Move)));
+        f.instruction(&Instruction::LocalGet(scratch.addr));
+        f.instruction(&Instruction::LocalGet(scratch.i32));
+    }
+    /// All three bulk operations take `(dst, x, len)`, where `x` is the source address of
+    /// a copy and is not reported otherwise.
+    fn emit_bulk_access(&self, f: &mut Function, scratch: &Scratch, hook: Hook, site: usize) {
+        f.instruction(&Instruction::LocalSet(scratch.len));
+        f.instruction(&Instruction::LocalSet(scratch.i32));
+        f.instruction(&Instruction::LocalTee(scratch.addr));
+        if hook == Hook::Copy {
+            f.instruction(&Instruction::LocalGet(scratch.i32));
+        }
+        f.instruction(&Instruction::LocalGet(scratch.len));
+        f.instruction(&Instruction::I32Const(site as i32));
+        f.instruction(&Instruction::Call(self.hook_func(hook)));
+        f.instruction(&Instruction::LocalGet(scratch.addr));
+        f.instruction(&Instruction::LocalGet(scratch.i32));
+        f.instruction(&Instruction::LocalGet(scratch.len));
+    }
+}
+
+impl Reencode for Instrumenter<'_> {
+    type Error = Infallible;
+
+    fn function_index(&mut self, func: u32) -> Result<u32, reencode::Error> {
+        match self.wrappers.iter().position(|w| w.func == func) {
+            Some(index) => Ok(self.wrapper_func(index)),
+            None => Ok(self.shifted(func)),
+        }
+    }
+
+    /// Emits the sections the module lacks but the hooks and wrappers need.
+    fn intersperse_section_hook(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        _after: Option<SectionId>,
+        before: Option<SectionId>,
+    ) -> Result<(), reencode::Error> {
+        let mut needed = vec![SectionId::Type, SectionId::Import];
+        if !self.wrappers.is_empty() {
+            needed.push(SectionId::Function);
+        }
+        if self.shadow_globals > 0 {
+            needed.push(SectionId::Global);
+        }
+        if self.root_globals().next().is_some() {
+            needed.push(SectionId::Export);
+        }
+        if !self.wrappers.is_empty() {
+            needed.push(SectionId::Code);
+        }
+        for id in needed {
+            let due = before.is_none_or(|before| section_rank(before) > section_rank(id));
+            if self.emitted.contains(&id) || !due {
+                continue;
+            }
+            match id {
+                SectionId::Type => {
+                    let mut types = wasm_encoder::TypeSection::new();
+                    self.add_hook_types(&mut types);
+                    module.section(&types);
+                }
+                SectionId::Import => {
+                    let mut imports = wasm_encoder::ImportSection::new();
+                    self.add_hook_imports(&mut imports);
+                    module.section(&imports);
+                }
+                SectionId::Function => {
+                    let mut functions = wasm_encoder::FunctionSection::new();
+                    self.add_wrapper_functions(&mut functions);
+                    module.section(&functions);
+                }
+                SectionId::Global => {
+                    let mut globals = wasm_encoder::GlobalSection::new();
+                    self.add_shadow_globals(&mut globals);
+                    module.section(&globals);
+                }
+                SectionId::Export => {
+                    let mut exports = wasm_encoder::ExportSection::new();
+                    self.add_global_exports(&mut exports);
+                    module.section(&exports);
+                }
+                _ => {
+                    let mut code = wasm_encoder::CodeSection::new();
+                    self.add_wrapper_bodies(&mut code);
+                    module.section(&code);
+                }
+            }
+            self.emitted.push(id);
+        }
+        Ok(())
+    }
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut wasm_encoder::TypeSection,
+        section: wasmparser::TypeSectionReader<'_>,
+    ) -> Result<(), reencode::Error> {
+        reencode::utils::parse_type_section(self, types, section)?;
+        self.add_hook_types(types);
+        self.emitted.push(SectionId::Type);
+        Ok(())
+    }
+
+    fn parse_import_section(
+        &mut self,
+        imports: &mut wasm_encoder::ImportSection,
+        section: wasmparser::ImportSectionReader<'_>,
+    ) -> Result<(), reencode::Error> {
+        reencode::utils::parse_import_section(self, imports, section)?;
+        self.add_hook_imports(imports);
+        self.emitted.push(SectionId::Import);
+        Ok(())
+    }
+
+    fn parse_function_section(
+        &mut self,
+        functions: &mut wasm_encoder::FunctionSection,
+        section: wasmparser::FunctionSectionReader<'_>,
+    ) -> Result<(), reencode::Error> {
+        reencode::utils::parse_function_section(self, functions, section)?;
+        self.add_wrapper_functions(functions);
+        self.emitted.push(SectionId::Function);
+        Ok(())
+    }
+
+    fn parse_global_section(
+        &mut self,
+        globals: &mut wasm_encoder::GlobalSection,
+        section: wasmparser::GlobalSectionReader<'_>,
+    ) -> Result<(), reencode::Error> {
+        reencode::utils::parse_global_section(self, globals, section)?;
+        self.add_shadow_globals(globals);
+        self.emitted.push(SectionId::Global);
+        Ok(())
+    }
+
+    fn parse_export_section(
+        &mut self,
+        exports: &mut wasm_encoder::ExportSection,
+        section: wasmparser::ExportSectionReader<'_>,
+    ) -> Result<(), reencode::Error> {
+        reencode::utils::parse_export_section(self, exports, section)?;
+        self.add_global_exports(exports);
+        self.emitted.push(SectionId::Export);
+        Ok(())
+    }
+
+    fn parse_code_section(
+        &mut self,
+        code: &mut wasm_encoder::CodeSection,
+        section: wasmparser::CodeSectionReader<'_>,
+    ) -> Result<(), reencode::Error> {
+        reencode::utils::parse_code_section(self, code, section)?;
+        self.add_wrapper_bodies(code);
+        self.emitted.push(SectionId::Code);
+        Ok(())
+    }
+
+    fn parse_custom_name_subsection(
+        &mut self,
+        names: &mut wasm_encoder::NameSection,
+        section: wasmparser::Name<'_>,
+    ) -> Result<(), reencode::Error> {
+        // names stay with the original functions rather than following the redirection
+        let shifted = |func| Ok(self.shifted(func));
+        match section {
+            Name::Function(map) => {
+                let mut map = reencode::utils::name_map(map, shifted)?;
+                for (index, wrapper) in self.wrappers.iter().enumerate() {
+                    let name = format!("{}.{}", HOOK_MODULE, wrapper.name);
+                    map.append(self.wrapper_func(index), &name);
+                }
+                names.functions(&map);
+            }
+            Name::Local(map) => names.locals(&reencode::utils::indirect_name_map(map, shifted)?),
+            Name::Label(map) if self.call_stacks => {
+                // the block wrapping each body comes first and shifts every other label
+                let mut labels = wasm_encoder::IndirectNameMap::new();
+                for naming in map {
+                    let naming = naming?;
+                    let mut map = wasm_encoder::NameMap::new();
+                    for label in naming.names {
+                        let label = label?;
+                        map.append(label.index + 1, label.name);
+                    }
+                    labels.append(self.shifted(naming.index), &map);
+                }
+                names.labels(&labels);
+            }
+            Name::Label(map) => names.labels(&reencode::utils::indirect_name_map(map, shifted)?),
+            section => reencode::utils::parse_custom_name_subsection(self, names, section)?,
+        }
+        Ok(())
+    }
+
+    fn parse_function_body(
+        &mut self,
+        code: &mut wasm_encoder::CodeSection,
+        func: wasmparser::FunctionBody<'_>,
+    ) -> Result<(), reencode::Error> {
+        let index = self.info.imported_funcs + self.next_func as u32;
+        let ty = self.info.funcs[index as usize];
+        self.next_func += 1;
+        let mut locals = Vec::new();
+        let mut num_locals = self.info.types[ty as usize]
+            .as_ref()
+            .map_or(0, |ty| ty.params().len() as u32);
+        for pair in func.get_locals_reader()? {
+            let (count, ty) = pair?;
+            locals.push((count, self.val_type(ty)?));
+            num_locals += count;
+        }
+        let scratch = Scratch {
+            addr: num_locals,
+            i32: num_locals + 1,
+            len: num_locals + 2,
+            i64: num_locals + 3,
+            f32: num_locals + 4,
+            f64: num_locals + 5,
+            v128: num_locals + 6,
+        };
+        let mut ops = Vec::new();
+        let mut reader = func.get_operators_reader()?;
+        while !reader.eof() {
+            ops.push(reader.read_with_offset()?);
+        }
+        locals.extend([
+            (3, ValType::I32),
+            (1, ValType::I64),
+            (1, ValType::F32),
+            (1, ValType::F64),
+        ]);
+        let uses_v128 = ops
+            .iter()
+            .filter_map(|(op, _)| memory_access(op))
+            .any(|access| access.value == Some(ValType::V128));
+        if uses_v128 {
+            locals.push((1, ValType::V128));
+        }
+        let shadows = match self.validators.pop_front() {
+            Some(validator) => {
+                let base = num_locals + 6 + uses_v128 as u32;
+                let hook = self.hook_func(Hook::UndefinedValue);
+                let ty = self.info.types[ty as usize].as_ref();
+                let linkage = Linkage {
+                    func: index,
+                    params: ty.map_or(0, |ty| ty.params().len() as u32),
+                    results: ty.map_or(0, |ty| ty.results().len() as u32),
+                    globals: self.info.globals.len() as u32,
+                };
+                ValueShadows::build(validator, &func, &ops, num_locals, base, hook, linkage)?
+            }
+            None => None,
+        };
+        if let Some(shadows) = &shadows {
+            locals.push((shadows.locals, ValType::I32));
+        }
+
+        let mut f = Function::new(locals);
+        for instruction in shadows.iter().flat_map(|shadows| &shadows.entry) {
+            f.instruction(instruction);
+        }
+        let exit = self
+            .call_stacks
+            .then(|| Instruction::Call(self.hook_func(Hook::Exit)));
+        if exit.is_some() {
+            f.instruction(&Instruction::I32Const(index as i32));
+            f.instruction(&Instruction::Call(self.hook_func(Hook::Enter)));
+            f.instruction(&Instruction::Block(self.body_block_type(ty)));
+        }
+        let last = ops.len() - 1;
+        let mut moved = None; // index of a store already reported as part of a move
+        for (i, (op, site)) in ops.iter().cloned().enumerate() {
+            if let Some(exit) = &exit {
+                match op {
+                    Operator::Return
+                    | Operator::ReturnCall { .. }
+                    | Operator::ReturnCallIndirect { .. }
+                    | Operator::ReturnCallRef { .. } => {
+                        f.instruction(exit);
+                    }
+                    // the end of the body: close the wrapping block first
+                    Operator::End if i == last => {
+                        f.instruction(&Instruction::End);
+                        f.instruction(exit);
+                    }
+                    _ => {}
+                }
+            }
+            let shadow = shadows.as_ref().map(|shadows| &shadows.ops[i]);
+            for instruction in shadow.iter().flat_map(|shadow| &shadow.before) {
+                f.instruction(instruction);
+            }
+            let access = memory_access(&op).filter(|_| moved != Some(i));
+            let store = ops.get(i + 1).and_then(|(next, _)| memory_access(next));
+            match (access, store) {
+                (Some(load), Some(store))
+                    if self.moves
+                        && load.hook == Hook::Load
+                        && load.value.is_none()
+                        && store.hook == Hook::Store
+                        && store.len == load.len =>
+                {
+                    self.emit_move(&mut f, &scratch, &load, &store, site);
+                    moved = Some(i + 1);
+                }
+                (Some(access), _) => {
+                    let value = shadow.and_then(|shadow| shadow.value);
+                    self.emit_access(&mut f, &scratch, &access, value, site);
+                }
+                (None, _) => {}
+            }
+            if let Some(hook) = bulk_access(&op) {
+                self.emit_bulk_access(&mut f, &scratch, hook, site);
+            }
+            if let Operator::GlobalSet { global_index } = op {
+                if Some(global_index) == self.stack_pointer {
+                    f.instruction(&Instruction::LocalTee(scratch.addr));
+                    f.instruction(&Instruction::Call(self.hook_func(Hook::StackPointer)));
+                    f.instruction(&Instruction::LocalGet(scratch.addr));
+                }
+            }
+            f.instruction(&self.instruction(op)?);
+            for instruction in shadow.iter().flat_map(|shadow| &shadow.after) {
+                f.instruction(instruction);
+            }
+        }
+        code.function(&f);
+        Ok(())
+    }
+}
+
+fn const_i32(expr: &wasmparser::ConstExpr) -> Option<u32> {
+    let mut ops = expr.get_operators_reader();
+    match (ops.read().ok()?, ops.read().ok()?) {
+        (Operator::I32Const { value }, Operator::End) => Some(value as u32),
+        _ => None,
+    }
+}
+
+/// Where an LLVM-produced module keeps its stack and static data.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Layout {
+    /// Initial value of the stack pointer, i.e. the top of the stack.
+    pub stack_top: Option<u32>,
+    /// The active data segments, extended up to `__data_end` (the end of `.bss`) if the
+    /// module names it.
+    pub static_data: Option<Range<u32>>,
+}
+
+/// Reads the memory layout of `wasm` (either before or after instrumentation).
+pub fn layout(wasm: &[u8], config: &Config) -> Result<Layout, InstrumentError> {
+    let info = ModuleInfo::parse(wasm)?;
+    let stack_top = config
+        .stack_pointer
+        .as_deref()
+        .and_then(|name| info.global_init(name));
+    let start = info.data.iter().map(|data| data.start).min();
+    let end = info
+        .data
+        .iter()
+        .map(|data| data.end)
+        .chain(info.global_init("__data_end"))
+        .max();
+    let static_data = start.zip(end).map(|(start, end)| start..end);
+    Ok(Layout {
+        stack_top,
+        static_data,
+    })
+}
+
+/// Function names and code ranges of a module, to tell which function a site lies in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Symbols {
+    imported_funcs: u32,
+    bodies: Vec<Range<usize>>, // sorted, one per defined function
+    names: HashMap<u32, String>,
+}
+
+impl Symbols {
+    /// Index of the function whose code contains the module offset `site`.
+    pub fn function_at(&self, site: usize) -> Option<u32> {
+        let index = self.bodies.partition_point(|body| body.end <= site);
+        let body = self.bodies.get(index)?;
+        body.contains(&site)
+            .then_some(self.imported_funcs + index as u32)
+    }
+    /// The function's name from the name section.
+    pub fn name(&self, func: u32) -> Option<&str> {
+        self.names.get(&func).map(String::as_str)
+    }
+}
+
+/// Reads the symbols of `wasm`. Sites refer to the module before instrumentation, so
+/// this should be given the original module.
+pub fn symbols(wasm: &[u8]) -> Result<Symbols, InstrumentError> {
+    let info = ModuleInfo::parse(wasm)?;
+    Ok(Symbols {
+        imported_funcs: info.imported_funcs,
+        bodies: info.bodies,
+        names: info.func_names,
+    })
+}
+
+/// Instruments every load and store of `wasm` with calls to the `HOOK_MODULE` hooks, and
+/// wraps the allocator functions named in `config`.
+pub fn instrument(wasm: &[u8], config: &Config) -> Result<Vec<u8>, InstrumentError> {
+    let info = ModuleInfo::parse(wasm)?;
+    let mut instrumenter = Instrumenter::new(&info, config)?;
+    if config.value_shadows {
+        instrumenter.validators = values::function_validators(wasm)?;
+    }
+    let mut module = wasm_encoder::Module::new();
+    instrumenter.parse_core_module(&mut module, Parser::new(0), wasm)?;
+    Ok(module.finish())
+}
+
+#[cfg(test)]
+fn instrumented_bodies(wat: &str) -> Vec<Vec<String>> {
+    instrumented_bodies_with(wat, &Config::new())
+}
+
+#[cfg(test)]
+fn instrumented_bodies_with(wat: &str, config: &Config) -> Vec<Vec<String>> {
+    let wasm = instrument(&wat::parse_str(wat).unwrap(), config).unwrap();
+    wasmparser::validate(&wasm).unwrap();
+    let mut bodies = Vec::new();
+    for payload in Parser::new(0).parse_all(&wasm) {
+        if let Payload::CodeSectionEntry(body) = payload.unwrap() {
+            let mut reader = body.get_operators_reader().unwrap();
+            let mut ops = Vec::new();
+            while !reader.eof() {
+                ops.push(format!("{:?}", reader.read().unwrap()));
+            }
+            bodies.push(ops);
+        }
+    }
+    bodies
+}
+
+#[test]
+fn hooks_loads_and_stores() {
+    let bodies = instrumented_bodies(
+        r#"(module
+            (memory 1)
+            (func (param i32) (result i64)
+                local.get 0
+                i64.const 7
+                i64.store offset=8
+                local.get 0
+                i64.load offset=8))"#,
+    );
+    let calls: Vec<_> = bodies[0]
+        .iter()
+        .filter(|op| op.starts_with("Call"))
+        .collect();
+    assert_eq!(
+        calls,
+        ["Call { function_index: 1 }", "Call { function_index: 0 }"]
+    );
+    assert!(bodies[0].contains(&"I32Const { value: 8 }".to_string()));
+}
+
+#[test]
+fn existing_imports_and_calls_are_remapped() {
+    let wasm = instrument(
+        &wat::parse_str(
+            r#"(module
+                (import "env" "f" (func $f))
+                (memory 1)
+                (func $g (call $f) (call $h))
+                (func $h (drop (i32.load8_u (i32.const 0))))
+                (export "g" (func $g))
+                (start $g))"#,
+        )
+        .unwrap(),
+        &Config::new(),
+    )
+    .unwrap();
+    wasmparser::validate(&wasm).unwrap();
+
+    let mut imports = Vec::new();
+    let mut exports = Vec::new();
+    let mut start = None;
+    for payload in Parser::new(0).parse_all(&wasm) {
+        match payload.unwrap() {
+            Payload::ImportSection(section) => {
+                for import in section.into_imports() {
+                    let import = import.unwrap();
+                    imports.push(format!("{}.{}", import.module, import.name));
+                }
+            }
+            Payload::ExportSection(section) => {
+                for export in section {
+                    exports.push(export.unwrap().index);
+                }
+            }
+            Payload::StartSection { func, .. } => start = Some(func),
+            _ => {}
+        }
+    }
+    assert_eq!(
+        imports,
+        ["env.f", "wasm_valgrind.load", "wasm_valgrind.store"]
+    );
+    assert_eq!(exports, [3]);
+    assert_eq!(start, Some(3));
+}
+
+#[test]
+fn module_without_imports_or_types() {
+    let bodies = instrumented_bodies(
+        r#"(module
+            (memory 1)
+            (data (i32.const 0) "hi"))"#,
+    );
+    assert!(bodies.is_empty());
+}
+
+#[test]
+fn simd_lane_accesses() {
+    let bodies = instrumented_bodies(
+        r#"(module
+            (memory 1)
+            (func (param v128) (result v128)
+                (v128.store32_lane 1 (i32.const 0) (local.get 0))
+                (v128.load8_lane 3 (i32.const 4) (local.get 0))))"#,
+    );
+    let calls: Vec<_> = bodies[0]
+        .iter()
+        .filter(|op| op.starts_with("Call"))
+        .collect();
+    assert_eq!(
+        calls,
+        ["Call { function_index: 1 }", "Call { function_index: 0 }"]
+    );
+}
+
+#[test]
+fn rejects_memory64() {
+    let wasm = wat::parse_str("(module (memory i64 1))").unwrap();
+    assert!(matches!(
+        instrument(&wasm, &Config::new()),
+        Err(InstrumentError::Unsupported(_))
+    ));
+}
+
+#[test]
+fn wraps_allocator_functions() {
+    let wat = r#"(module
+        (memory 1)
+        (table 1 funcref)
+        (elem (i32.const 0) $malloc)
+        (func $malloc (export "malloc") (param i32) (result i32) (i32.const 4096))
+        (func $free (param i32))
+        (func $main (drop (call $malloc (i32.const 8))) (call $free (i32.const 4096))))"#;
+    let mut config = Config::new();
+    config.set_allocator(AllocatorNames::default());
+    let wasm = instrument(&wat::parse_str(wat).unwrap(), &config).unwrap();
+    wasmparser::validate(&wasm).unwrap();
+
+    // load, store, malloc, free, alloc_enter, alloc_exit are imported as 0..6
+    let mut imports = Vec::new();
+    let mut exports = Vec::new();
+    let mut elements = Vec::new();
+    let mut bodies = Vec::new();
+    let mut names = Vec::new();
+    for payload in Parser::new(0).parse_all(&wasm) {
+        match payload.unwrap() {
+            Payload::ImportSection(section) => {
+                for import in section.into_imports() {
+                    imports.push(import.unwrap().name);
+                }
+            }
+            Payload::ExportSection(section) => {
+                for export in section {
+                    exports.push(export.unwrap().index);
+                }
+            }
+            Payload::ElementSection(section) => {
+                for element in section {
+                    if let wasmparser::ElementItems::Functions(funcs) = element.unwrap().items {
+                        elements.extend(funcs.into_iter().map(Result::unwrap));
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                let mut reader = body.get_operators_reader().unwrap();
+                let mut calls = Vec::new();
+                while !reader.eof() {
+                    if let Operator::Call { function_index } = reader.read().unwrap() {
+                        calls.push(function_index);
+                    }
+                }
+                bodies.push(calls);
+            }
+            Payload::CustomSection(section) => {
+                if let KnownCustom::Name(reader) = section.as_known() {
+                    for name in reader {
+                        if let Name::Function(map) = name.unwrap() {
+                            for naming in map {
+                                let naming = naming.unwrap();
+                                names.push((naming.index, naming.name.to_string()));
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(
+        imports,
+        [
+            "load",
+            "store",
+            "malloc",
+            "free",
+            "alloc_enter",
+            "alloc_exit"
+        ]
+    );
+    assert_eq!(exports, [9]);
+    assert_eq!(elements, [9]);
+    assert_eq!(bodies[2], [9, 10]);
+    // malloc wrapper: enter, original, exit, hook
+    assert_eq!(bodies[3], [4, 6, 5, 2]);
+    // free wrapper: hook, enter, original, exit
+    assert_eq!(bodies[4], [3, 4, 7, 5]);
+    assert_eq!(names[0], (6, "malloc".to_string()));
+    assert_eq!(names[3], (9, "wasm_valgrind.malloc".to_string()));
+}
+
+#[test]
+fn allocator_with_unexpected_signature() {
+    let wat = r#"(module (func (export "malloc") (param i32 i32) (result i32) (i32.const 0)))"#;
+    let mut config = Config::new();
+    config.set_allocator(AllocatorNames::default());
+    assert!(matches!(
+        instrument(&wat::parse_str(wat).unwrap(), &config),
+        Err(InstrumentError::AllocatorSignature { .. })
+    ));
+}
+
+#[test]
+fn hooks_stack_pointer_writes() {
+    let bodies = instrumented_bodies(
+        r#"(module
+            (global $__stack_pointer (mut i32) (i32.const 1024))
+            (global $other (mut i32) (i32.const 0))
+            (func
+                (global.set $__stack_pointer (i32.sub (global.get $__stack_pointer) (i32.const 16)))
+                (global.set $other (i32.const 1))))"#,
+    );
+    let calls: Vec<_> = bodies[0]
+        .iter()
+        .filter(|op| op.starts_with("Call"))
+        .collect();
+    assert_eq!(calls, ["Call { function_index: 2 }"]);
+
+    let mut config = Config::new();
+    config.set_stack_pointer(None);
+    let wasm = wat::parse_str(
+        r#"(module
+            (global (export "__stack_pointer") (mut i32) (i32.const 1024))
+            (func (global.set 0 (i32.const 0))))"#,
+    )
+    .unwrap();
+    let imports = |wasm: &[u8]| {
+        let mut names = Vec::new();
+        for payload in Parser::new(0).parse_all(wasm) {
+            if let Payload::ImportSection(section) = payload.unwrap() {
+                for import in section.into_imports() {
+                    names.push(import.unwrap().name.to_string());
+                }
+            }
+        }
+        names
+    };
+    assert_eq!(
+        imports(&instrument(&wasm, &Config::new()).unwrap()),
+        ["load", "store", "stack_pointer"]
+    );
+    assert_eq!(
+        imports(&instrument(&wasm, &config).unwrap()),
+        ["load", "store"]
+    );
+}
+
+#[test]
+fn hooks_function_entry_and_exit() {
+    let mut config = Config::new();
+    config.set_call_stacks(true);
+    let bodies = instrumented_bodies_with(
+        r#"(module
+            (func $pair (param i32) (result i32 i64)
+                (block $done
+                    (br_if 1 (i32.const 1) (i64.const 2) (local.get 0))
+                    (br $done))
+                (return (i32.const 3) (i64.const 4)))
+            (func $tail (result i32 i64) (return_call $pair (i32.const 0))))"#,
+        &config,
+    );
+    let hooks = ["Call { function_index: 2 }", "Call { function_index: 3 }"];
+    let calls = |body: &[String]| {
+        body.iter()
+            .filter(|op| hooks.contains(&op.as_str()) || op.starts_with("Return"))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        bodies[0][..3],
+        [
+            "I32Const { value: 0 }",
+            hooks[0],
+            "Block { blockty: FuncType(6) }"
+        ]
+    );
+    assert_eq!(calls(&bodies[0]), [hooks[0], hooks[1], "Return", hooks[1]]);
+    assert_eq!(bodies[0][bodies[0].len() - 3..], ["End", hooks[1], "End"]);
+    assert_eq!(
+        calls(&bodies[1]),
+        [
+            hooks[0],
+            hooks[1],
+            "ReturnCall { function_index: 4 }",
+            hooks[1]
+        ]
+    );
+}
+
+#[test]
+fn reads_layout() {
+    let wasm = wat::parse_str(
+        r#"(module
+            (memory 2)
+            (global $__stack_pointer (mut i32) (i32.const 65536))
+            (global (export "__data_end") i32 (i32.const 65600))
+            (data (i32.const 65536) "hello")
+            (data (i32.const 65544) "world")
+            (data "passive"))"#,
+    )
+    .unwrap();
+    let found = layout(&wasm, &Config::new()).unwrap();
+    assert_eq!(found.stack_top, Some(65536));
+    assert_eq!(found.static_data, Some(65536..65600));
+
+    let wasm = wat::parse_str("(module (memory 1))").unwrap();
+    assert_eq!(layout(&wasm, &Config::new()).unwrap(), Layout::default());
+
+    let wasm = wat::parse_str(r#"(module (memory 1) (data (i32.const -2) "hello"))"#).unwrap();
+    let found = layout(&wasm, &Config::new()).unwrap();
+    assert_eq!(found.static_data, Some(u32::MAX - 1..u32::MAX));
+}
+
+#[test]
+fn reads_symbols() {
+    let wasm = wat::parse_str(
+        r#"(module
+            (import "env" "f" (func))
+            (memory 1)
+            (func $first (drop (i32.load (i32.const 0))))
+            (func (export "second") (i32.store (i32.const 0) (i32.const 1))))"#,
+    )
+    .unwrap();
+    let found = symbols(&wasm).unwrap();
+    let mut sites = Vec::new();
+    for payload in Parser::new(0).parse_all(&wasm) {
+        if let Payload::CodeSectionEntry(body) = payload.unwrap() {
+            let mut reader = body.get_operators_reader().unwrap();
+            while !reader.eof() {
+                let (op, offset) = reader.read_with_offset().unwrap();
+                if memory_access(&op).is_some() {
+                    sites.push(offset);
+                }
+            }
+        }
+    }
+    assert_eq!(found.function_at(sites[0]), Some(1));
+    assert_eq!(found.function_at(sites[1]), Some(2));
+    assert_eq!(found.function_at(0), None);
+    assert_eq!(found.name(1), Some("first"));
+    assert_eq!(found.name(2), None);
+}
+
+#[test]
+fn hooks_bulk_memory() {
+    let wat = r#"(module
+        (memory 1)
+        (data $passive "abcd")
+        (func (param i32)
+            (memory.copy (local.get 0) (i32.const 16) (i32.const 8))
+            (memory.fill (local.get 0) (i32.const 0) (i32.const 8))
+            (memory.init $passive (local.get 0) (i32.const 1) (i32.const 2))
+            (data.drop $passive)))"#;
+    let bodies = instrumented_bodies(wat);
+    let calls: Vec<_> = bodies[0]
+        .iter()
+        .filter(|op| op.starts_with("Call"))
+        .collect();
+    // load, store, copy and fill are imported in that order
+    assert_eq!(
+        calls,
+        [
+            "Call { function_index: 2 }",
+            "Call { function_index: 3 }",
+            "Call { function_index: 3 }"
+        ]
+    );
+    let copy = bodies[0].iter().position(|op| op.starts_with("MemoryCopy"));
+    let hook = bodies[0]
+        .iter()
+        .position(|op| op == "Call { function_index: 2 }");
+    assert!(hook < copy);
+
+    let plain = instrumented_bodies("(module (memory 1) (func (memory.size) (drop)))");
+    assert!(!plain[0].iter().any(|op| op.starts_with("Call")));
+}
+
+#[test]
+fn reports_copied_values_as_moves() {
+    let wat = r#"(module
+        (memory 1)
+        (func (param i32 i32)
+            (i64.store offset=8 (local.get 0) (i64.load offset=16 (local.get 1)))
+            (i32.store16 (local.get 0) (i32.load (local.get 1)))))"#;
+    let mut config = Config::new();
+    config.set_moves(true);
+    let bodies = instrumented_bodies_with(wat, &config);
+    let calls: Vec<_> = bodies[0]
+        .iter()
+        .filter(|op| op.starts_with("Call"))
+        .collect();
+    // load, store and move_value; the narrower store is not a move
+    assert_eq!(
+        calls,
+        [
+            "Call { function_index: 2 }",
+            "Call { function_index: 0 }",
+            "Call { function_index: 1 }"
+        ]
+    );
+    let hook = bodies[0]
+        .iter()
+        .position(|op| op == "Call { function_index: 2 }");
+    // dst_offset, src, src_offset and len, followed by the site
+    let args = &bodies[0][hook.unwrap() - 5..hook.unwrap() - 1];
+    assert_eq!(
+        args,
+        [
+            "I32Const { value: 8 }",
+            "LocalGet { local_index: 3 }",
+            "I32Const { value: 16 }",
+            "I32Const { value: 8 }"
+        ]
+    );
+}
+
+#[test]
+fn tracks_value_definedness() {
+    let wat = r#"(module
+        (memory 1)
+        (table 1 funcref)
+        (type $t (func (param i32) (result i32)))
+        (func $f (type $t) (param i32) (result i32)
+            (i32.load (local.get 0))
+            (i32.const 7)
+            (block $b (param i32) (result i32)
+                (i32.const 1)
+                (i32.const 2)
+                (br_table $b 1 $b (local.get 0))
+                (unreachable))
+            (loop $l (param i32 i32) (result i32)
+                (br_if $l (i32.const 3) (i32.const 4) (local.get 0))
+                (drop)
+                (drop)
+                (select (local.get 0)))
+            (call_indirect (type $t) (i32.const 0))
+            (if (result i32) (then (i32.const 8)) (else (i32.const 9)))
+            (i32.store (i32.const 16) (local.tee 0))
+            (i32.const 0))
+        (func (try_table (throw 0)))
+        (tag))"#;
+    let mut config = Config::new();
+    config.set_value_shadows(true);
+    let bodies = instrumented_bodies_with(wat, &config);
+    let calls = |body: &[String]| {
+        body.iter()
+            .filter(|op| op.starts_with("Call {"))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    // load_value, store_value and undefined_value follow load and store
+    let hook = |index| format!("Call {{ function_index: {} }}", index);
+    let body = &bodies[0];
+    let used = |point: ValueUse| {
+        let point = format!("I32Const {{ value: {} }}", point.code());
+        (2..body.len())
+            .filter(|i| body[*i] == hook(4) && body[*i - 2] == point)
+            .count()
+    };
+    let accesses = calls(body)
+        .into_iter()
+        .filter(|call| *call != hook(4))
+        .collect::<Vec<_>>();
+    assert_eq!(accesses, [hook(2), hook(3)]);
+    // two addresses, the br_table, br_if, select and if conditions, and the table index
+    assert_eq!(used(ValueUse::Address), 2);
+    assert_eq!(used(ValueUse::Branch), 4);
+    assert_eq!(used(ValueUse::IndirectCall), 1);
+    // exception handling is left alone
+    assert!(calls(&bodies[1]).is_empty());
+}
+
+#[test]
+fn exports_global_roots() {
+    let wat = r#"(module
+        (import "env" "g" (global i32))
+        (global (mut i32) (i32.const 0))
+        (global (export "kept") i32 (i32.const 1))
+        (global (mut i64) (i64.const 0))
+        (global f32 (f32.const 0))
+        (func (export "f")))"#;
+    let exports = |config: &Config| {
+        let wasm = instrument(&wat::parse_str(wat).unwrap(), config).unwrap();
+        wasmparser::validate(&wasm).unwrap();
+        let mut names = Vec::new();
+        for payload in Parser::new(0).parse_all(&wasm) {
+            if let Payload::ExportSection(section) = payload.unwrap() {
+                for export in section {
+                    names.push(export.unwrap().name.to_string());
+                }
+            }
+        }
+        names
+    };
+    assert_eq!(exports(&Config::new()), ["kept", "f"]);
+    let mut config = Config::new();
+    config.set_global_roots(true);
+    assert_eq!(
+        exports(&config),
+        [
+            "kept",
+            "f",
+            "wasm_valgrind.global.1",
+            "wasm_valgrind.global.3"
+        ]
+    );
+
+    // modules without an export section get one
+    let wasm = wat::parse_str("(module (global (mut i32) (i32.const 0)))").unwrap();
+    let wasm = instrument(&wasm, &config).unwrap();
+    wasmparser::validate(&wasm).unwrap();
+    assert!(String::from_utf8_lossy(&wasm).contains("wasm_valgrind.global.0"));
+}
@@ -0,0 +1,504 @@
+/*
+Definedness shadows for wasm values. Every local and every operand stack slot gets an
+i32 shadow local that is nonzero while the value is undefined; the slot of an operand
+is its height on the whole function's operand stack, as computed by the validator. The
+code maintaining the shadows runs before each instruction, which leaves the operand
+stack as it found it:
+
+- constants and globals are defined; every other instruction's results are undefined
+  if any of its operands is;
+- a load's result is undefined if any of the loaded bytes is, as told by `load_value`,
+  and `store_value` is told whether the stored value is;
+- a branch copies the shadows of the values it carries to the slots of its label,
+  under a condition for `br_if` and `br_table`, whose other targets may sit lower;
+- the conditions of `br_if`, `if`, `select` and `br_table`, the index of
+  `call_indirect` and the address of a load or store are checked, and reported to
+  `undefined_value` if undefined.
+
+Direct calls pass shadows through a set of mutable i32 globals (see `Linkage`): the
+caller stores the shadows of the arguments and names the callee in the callee tag,
+and the callee takes them on entry if the tag names it. On the way out the callee
+stores the shadows of its results and names itself in the result tag, which the caller
+checks after the call. Functions entered from the host, an import or an indirect call,
+and calls answered by a function without shadows, an import or a tail call, find a tag
+naming someone else and count the values as defined; the parameters and results of
+indirect calls always do.
+
+Functions using exception handling, stack switching or the GC branch instructions are
+left without shadows.
+*/
+
+use super::{memory_access, Hook, InstrumentError};
+use crate::ValueUse;
+use std::collections::VecDeque;
+use wasm_encoder::{BlockType, Instruction};
+use wasmparser::{
+    FrameKind, FuncToValidate, FuncValidator, FunctionBody, ModuleArity, Operator, Parser,
+    ValidPayload, Validator, ValidatorResources, WasmFeatures,
+};
+
+/// Validates `wasm`, returning the validator of each defined function.
+pub(super) fn function_validators(
+    wasm: &[u8],
+) -> Result<VecDeque<FuncToValidate<ValidatorResources>>, InstrumentError> {
+    let mut validator = Validator::new_with_features(WasmFeatures::all());
+    let mut funcs = VecDeque::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        if let ValidPayload::Func(func, _) = validator.payload(&payload?)? {
+            funcs.push_back(func);
+        }
+    }
+    Ok(funcs)
+}
+
+/// How a function exchanges the shadows of its parameters and results with its callers.
+#[derive(Clone, Copy)]
+pub(super) struct Linkage {
+    pub func: u32, // the function's index in the original module, naming it in the tags
+    pub params: u32,
+    pub results: u32,
+    /// The first of the shadow globals: the callee tag, the result tag, then one global
+    /// per parameter or result of the widest function type.
+    pub globals: u32,
+}
+
+impl Linkage {
+    /// The number of globals needed for function types of up to `arity` parameters or
+    /// results.
+    pub(super) fn globals(arity: u32) -> u32 {
+        2 + arity
+    }
+    fn callee_tag(&self) -> u32 {
+        self.globals
+    }
+    fn result_tag(&self) -> u32 {
+        self.globals + 1
+    }
+    fn value(&self, i: u32) -> u32 {
+        self.globals + 2 + i
+    }
+}
+
+/// Shadow code of one instruction.
+#[derive(Default)]
+pub(super) struct OpShadow {
+    pub before: Vec<Instruction<'static>>,
+    pub after: Vec<Instruction<'static>>,
+    /// For a load, the shadow receiving the result of `load_value`; for a store, the
+    /// shadow passed to `store_value`.
+    pub value: Option<u32>,
+}
+
+pub(super) struct ValueShadows {
+    /// Takes the shadows of the parameters from the caller, run before the body.
+    pub entry: Vec<Instruction<'static>>,
+    pub ops: Vec<OpShadow>,
+    pub locals: u32, // i32 locals to append to the function
+}
+
+struct Builder {
+    temp: u32,
+    locals: u32, // shadow of local 0
+    slots: u32,  // shadow of the operand at height 0
+    height: u32, // slots used so far
+    hook: u32,
+    site: usize, // of the current instruction
+    linkage: Linkage,
+}
+
+impl Builder {
+    fn slot(&mut self, height: u32) -> u32 {
+        self.height = self.height.max(height + 1);
+        self.slots + height
+    }
+    fn check(&mut self, code: &mut Vec<Instruction<'static>>, point: ValueUse, slot: u32) {
+        code.extend([
+            Instruction::LocalGet(slot),
+            Instruction::If(BlockType::Empty),
+            Instruction::I32Const(point.code() as i32),
+            Instruction::I32Const(self.site as i32),
+            Instruction::Call(self.hook),
+            Instruction::End,
+        ]);
+    }
+    fn set(&mut self, code: &mut Vec<Instruction<'static>>, from: u32, to: u32) {
+        code.extend([Instruction::LocalGet(from), Instruction::LocalSet(to)]);
+    }
+    /// Copies of the shadows of the values carried by a branch to label `depth`, which
+    /// are on top of an operand stack of `height`. `None` for branches whose values are
+    /// already in place.
+    fn branch(
+        &mut self,
+        validator: &FuncValidator<ValidatorResources>,
+        depth: u32,
+        height: u32,
+    ) -> Option<Vec<Instruction<'static>>> {
+        if depth + 1 >= validator.control_stack_height() {
+            return self.exit(height);
+        }
+        let frame = validator.get_control_frame(depth as usize)?;
+        let (params, results) = validator.block_type_arity(frame.block_type)?;
+        let len = match frame.kind {
+            FrameKind::Loop => params,
+            _ => results,
+        };
+        let (src, dst) = (height - len, frame.height as u32);
+        if len == 0 || src == dst {
+            return None;
+        }
+        let mut code = Vec::new();
+        for i in 0..len {
+            let (from, to) = (self.slot(src + i), self.slot(dst + i));
+            self.set(&mut code, from, to);
+        }
+        Some(code)
+    }
+    /// Hands the shadows of the function's results, on top of an operand stack of
+    /// `height`, to the caller. `None` for functions without results.
+    fn exit(&mut self, height: u32) -> Option<Vec<Instruction<'static>>> {
+        let linkage = self.linkage;
+        if linkage.results == 0 {
+            return None;
+        }
+        let mut code = Vec::new();
+        for i in 0..linkage.results {
+            let slot = self.slot(height - linkage.results + i);
+            code.extend([
+                Instruction::LocalGet(slot),
+                Instruction::GlobalSet(linkage.value(i)),
+            ]);
+        }
+        code.extend([
+            Instruction::I32Const(linkage.func as i32),
+            Instruction::GlobalSet(linkage.result_tag()),
+        ]);
+        Some(code)
+    }
+    fn zero(&mut self, code: &mut Vec<Instruction<'static>>, slot: u32) {
+        code.extend([Instruction::I32Const(0), Instruction::LocalSet(slot)]);
+    }
+    /// Shadow code of `op`, run on an operand stack of `height`.
+    fn op(
+        &mut self,
+        validator: &FuncValidator<ValidatorResources>,
+        op: &Operator,
+        height: u32,
+    ) -> Option<OpShadow> {
+        let mut code = Vec::new();
+        let mut after = Vec::new();
+        let mut value = None;
+        let (params, results) = op.operator_arity(validator)?;
+        let linkage = self.linkage;
+        match op {
+            Operator::LocalGet { local_index } => {
+                let slot = self.slot(height);
+                self.set(&mut code, self.locals + local_index, slot);
+            }
+            Operator::LocalSet { local_index } | Operator::LocalTee { local_index } => {
+                let slot = self.slot(height - 1);
+                self.set(&mut code, slot, self.locals + local_index);
+            }
+            // the end of the body returns
+            Operator::End if validator.control_stack_height() == 1 => {
+                code.extend(self.exit(height).into_iter().flatten());
+            }
+            // block parameters and results keep their stack heights
+            Operator::Block { .. } | Operator::Loop { .. } | Operator::Else | Operator::End => {}
+            Operator::If { .. } => {
+                let slot = self.slot(height - 1);
+                self.check(&mut code, ValueUse::Branch, slot);
+            }
+            Operator::Br { relative_depth } => {
+                code.extend(
+                    self.branch(validator, *relative_depth, height)
+                        .into_iter()
+                        .flatten(),
+                );
+            }
+            Operator::BrIf { relative_depth } => {
+                let slot = self.slot(height - 1);
+                self.check(&mut code, ValueUse::Branch, slot);
+                if let Some(copies) = self.branch(validator, *relative_depth, height - 1) {
+                    code.extend([
+                        Instruction::LocalTee(self.temp),
+                        Instruction::If(BlockType::Empty),
+                    ]);
+                    code.extend(copies);
+                    code.extend([Instruction::End, Instruction::LocalGet(self.temp)]);
+                }
+            }
+            Operator::BrTable { targets } => {
+                let slot = self.slot(height - 1);
+                self.check(&mut code, ValueUse::Branch, slot);
+                code.push(Instruction::LocalTee(self.temp));
+                // the indices leading to each label, `None` standing for the default
+                let mut labels: Vec<(u32, Vec<Option<u32>>)> = Vec::new();
+                let indices = targets.targets().enumerate();
+                let indices = indices.map(|(i, depth)| depth.map(|depth| (Some(i as u32), depth)));
+                for target in indices.chain([Ok((None, targets.default()))]) {
+                    let (index, depth) = target.ok()?;
+                    match labels.iter_mut().find(|(label, _)| *label == depth) {
+                        Some((_, indices)) => indices.push(index),
+                        None => labels.push((depth, vec![index])),
+                    }
+                }
+                for (depth, indices) in labels {
+                    let Some(copies) = self.branch(validator, depth, height - 1) else {
+                        continue;
+                    };
+                    for (i, index) in indices.iter().enumerate() {
+                        code.push(Instruction::LocalGet(self.temp));
+                        match index {
+                            Some(index) => {
+                                code.extend([
+                                    Instruction::I32Const(*index as i32),
+                                    Instruction::I32Eq,
+                                ]);
+                            }
+                            None => {
+                                code.extend([
+                                    Instruction::I32Const(targets.len() as i32),
+                                    Instruction::I32GeU,
+                                ]);
+                            }
+                        }
+                        if i > 0 {
+                            code.push(Instruction::I32Or);
+                        }
+                    }
+                    code.push(Instruction::If(BlockType::Empty));
+                    code.extend(copies);
+                    code.push(Instruction::End);
+                }
+            }
+            Operator::Select | Operator::TypedSelect { .. } => {
+                let (first, second, cond) = (
+                    self.slot(height - 3),
+                    self.slot(height - 2),
+                    self.slot(height - 1),
+                );
+                self.check(&mut code, ValueUse::Branch, cond);
+                code.extend([
+                    Instruction::LocalTee(self.temp),
+                    Instruction::LocalGet(first),
+                    Instruction::LocalGet(second),
+                    Instruction::LocalGet(self.temp),
+                    Instruction::Select,
+                    Instruction::LocalSet(first),
+                ]);
+            }
+            Operator::Call { function_index } | Operator::ReturnCall { function_index } => {
+                let first = height - params;
+                for i in 0..params {
+                    let slot = self.slot(first + i);
+                    code.extend([
+                        Instruction::LocalGet(slot),
+                        Instruction::GlobalSet(linkage.value(i)),
+                    ]);
+                }
+                if params > 0 {
+                    code.extend([
+                        Instruction::I32Const(*function_index as i32),
+                        Instruction::GlobalSet(linkage.callee_tag()),
+                    ]);
+                }
+                // a tag left over from an earlier call must not answer this one
+                code.extend([
+                    Instruction::I32Const(-1),
+                    Instruction::GlobalSet(linkage.result_tag()),
+                ]);
+                if let Operator::Call { .. } = op {
+                    if results > 0 {
+                        after.extend([
+                            Instruction::GlobalGet(linkage.result_tag()),
+                            Instruction::I32Const(*function_index as i32),
+                            Instruction::I32Eq,
+                            Instruction::LocalSet(self.temp),
+                        ]);
+                    }
+                    for i in 0..results {
+                        let slot = self.slot(first + i);
+                        after.extend([
+                            Instruction::GlobalGet(linkage.value(i)),
+                            Instruction::I32Const(0),
+                            Instruction::LocalGet(self.temp),
+                            Instruction::Select,
+                            Instruction::LocalSet(slot),
+                        ]);
+                    }
+                }
+            }
+            Operator::CallRef { .. }
+            | Operator::CallIndirect { .. }
+            | Operator::ReturnCallIndirect { .. } => {
+                if let Operator::CallIndirect { .. } | Operator::ReturnCallIndirect { .. } = op {
+                    let slot = self.slot(height - 1);
+                    self.check(&mut code, ValueUse::IndirectCall, slot);
+                }
+                let first = height - params;
+                for i in 0..results {
+                    let slot = self.slot(first + i);
+                    self.zero(&mut code, slot);
+                }
+                if let Operator::ReturnCallIndirect { .. } = op {
+                    code.extend([
+                        Instruction::I32Const(-1),
+                        Instruction::GlobalSet(linkage.result_tag()),
+                    ]);
+                }
+            }
+            Operator::ReturnCallRef { .. } => {
+                code.extend([
+                    Instruction::I32Const(-1),
+                    Instruction::GlobalSet(linkage.result_tag()),
+                ]);
+            }
+            Operator::Return => {
+                code.extend(self.exit(height).into_iter().flatten());
+            }
+            _ => match memory_access(op) {
+                Some(access) if access.hook == Hook::Load && access.value.is_some() => {
+                    // a lane load: the vector keeps the shadow of the vector it updates
+                    let (addr, vector) = (self.slot(height - 2), self.slot(height - 1));
+                    self.check(&mut code, ValueUse::Address, addr);
+                    self.set(&mut code, vector, addr);
+                }
+                Some(access) if access.hook == Hook::Load => {
+                    let addr = self.slot(height - 1);
+                    self.check(&mut code, ValueUse::Address, addr);
+                    value = Some(addr);
+                }
+                Some(_) => {
+                    let addr = self.slot(height - 2);
+                    self.check(&mut code, ValueUse::Address, addr);
+                    value = Some(self.slot(height - 1));
+                }
+                None if results == 0 => {}
+                None if params == 0 => {
+                    for i in 0..results {
+                        let slot = self.slot(height + i);
+                        self.zero(&mut code, slot);
+                    }
+                }
+                None => {
+                    let first = self.slot(height - params);
+                    for i in 1..params {
+                        let slot = self.slot(height - params + i);
+                        code.extend([
+                            Instruction::LocalGet(first),
+                            Instruction::LocalGet(slot),
+                            Instruction::I32Or,
+                            Instruction::LocalSet(first),
+                        ]);
+                    }
+                    for i in 1..results {
+                        let slot = self.slot(height - params + i);
+                        self.set(&mut code, first, slot);
+                    }
+                }
+            },
+        }
+        Some(OpShadow {
+            before: code,
+            after,
+            value,
+        })
+    }
+}
+
+/// Whether `op` branches in ways the shadows do not follow.
+fn unsupported(op: &Operator) -> bool {
+    matches!(
+        op,
+        Operator::TryTable { .. }
+            | Operator::Try { .. }
+            | Operator::Catch { .. }
+            | Operator::CatchAll
+            | Operator::Rethrow { .. }
+            | Operator::Delegate { .. }
+            | Operator::BrOnNull { .. }
+            | Operator::BrOnNonNull { .. }
+            | Operator::BrOnCast { .. }
+            | Operator::BrOnCastFail { .. }
+            | Operator::Resume { .. }
+            | Operator::ResumeThrow { .. }
+            | Operator::ResumeThrowRef { .. }
+            | Operator::Switch { .. }
+            | Operator::TypedSelectMulti { .. }
+    )
+}
+
+impl ValueShadows {
+    /// Builds the shadow code of a function with `num_locals` locals (parameters
+    /// included), whose shadow locals start at `base`. `hook` is the function index of
+    /// `undefined_value`. `None` if the function is left without shadows.
+    pub(super) fn build(
+        func: FuncToValidate<ValidatorResources>,
+        body: &FunctionBody,
+        ops: &[(Operator, usize)],
+        num_locals: u32,
+        base: u32,
+        hook: u32,
+        linkage: Linkage,
+    ) -> Result<Option<ValueShadows>, wasmparser::BinaryReaderError> {
+        if ops.iter().any(|(op, _)| unsupported(op)) {
+            return Ok(None);
+        }
+        let mut validator = func.into_validator(Default::default());
+        // the stack heights are only meaningful in valid code
+        validator.clone().validate(body)?;
+        validator.read_locals(&mut body.get_binary_reader())?;
+        let mut builder = Builder {
+            temp: base,
+            locals: base + 1,
+            slots: base + 1 + num_locals,
+            height: 0,
+            hook,
+            site: 0,
+            linkage,
+        };
+        let mut entry = Vec::new();
+        if linkage.params > 0 {
+            entry.extend([
+                Instruction::GlobalGet(linkage.callee_tag()),
+                Instruction::I32Const(linkage.func as i32),
+                Instruction::I32Eq,
+                Instruction::If(BlockType::Empty),
+            ]);
+            for i in 0..linkage.params {
+                entry.extend([
+                    Instruction::GlobalGet(linkage.value(i)),
+                    Instruction::LocalSet(builder.locals + i),
+                ]);
+            }
+            entry.extend([
+                Instruction::I32Const(-1),
+                Instruction::GlobalSet(linkage.callee_tag()),
+                Instruction::End,
+            ]);
+        }
+        let mut shadows = Vec::with_capacity(ops.len());
+        for (op, site) in ops {
+            let frame = validator.get_control_frame(0);
+            let shadow = match frame {
+                // unreachable code never runs, and its stack heights are meaningless
+                Some(frame) if !frame.unreachable => {
+                    builder.site = *site;
+                    let height = validator.operand_stack_height();
+                    match builder.op(&validator, op, height) {
+                        Some(shadow) => shadow,
+                        None => return Ok(None),
+                    }
+                }
+                _ => OpShadow::default(),
+            };
+            shadows.push(shadow);
+            validator.op(*site, op)?;
+        }
+        Ok(Some(ValueShadows {
+            entry,
+            ops: shadows,
+            locals: 1 + num_locals + builder.height,
+        }))
+    }
+}
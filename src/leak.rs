@@ -0,0 +1,305 @@
+/*
+Leak checking follows memcheck: every live block is a candidate leak. Roots are
+the initialized words of memory outside the heap blocks (stack, globals and
+static data) plus any host-provided root values, such as wasm globals. A block is
+still reachable if a chain of pointers to its start leads to it from a root,
+possibly lost if every chain leading to it passes through an interior pointer,
+and definitely lost if none does. Pointers are little-endian 32-bit words at
+4-byte alignment.
+*/
+
+use crate::{MemState, Valgrind};
+use std::collections::BTreeMap;
+
+const POINTER_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeakKind {
+    DefinitelyLost,
+    PossiblyLost,
+    StillReachable,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeakedBlock {
+    pub addr: usize,
+    pub len: usize,
+    pub kind: LeakKind,
+    pub alloc_stack: Box<[u32]>, // as in `Block`
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LeakTotal {
+    pub blocks: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeakReport {
+    pub blocks: Vec<LeakedBlock>, // sorted by address
+}
+
+impl LeakReport {
+    pub fn definitely_lost(&self) -> LeakTotal {
+        self.total(LeakKind::DefinitelyLost)
+    }
+    pub fn possibly_lost(&self) -> LeakTotal {
+        self.total(LeakKind::PossiblyLost)
+    }
+    pub fn still_reachable(&self) -> LeakTotal {
+        self.total(LeakKind::StillReachable)
+    }
+    /// Whether no block is definitely or possibly lost, the kinds memcheck counts as errors.
+    pub fn is_leak_free(&self) -> bool {
+        self.definitely_lost().blocks == 0 && self.possibly_lost().blocks == 0
+    }
+    fn total(&self, kind: LeakKind) -> LeakTotal {
+        self.blocks.iter().filter(|block| block.kind == kind).fold(
+            LeakTotal::default(),
+            |total, block| LeakTotal {
+                blocks: total.blocks + 1,
+                bytes: total.bytes + block.len,
+            },
+        )
+    }
+}
+
+impl Valgrind {
+    /// Reports every block that was never freed. `memory` is the guest's linear memory and
+    /// `roots` holds extra pointer values the scan cannot see, such as wasm globals.
+    pub fn leak_check(&self, memory: &[u8], roots: &[usize]) -> LeakReport {
+        let blocks = &self.mallocs;
+        // the best kind of chain found to each block so far; blocks are rescanned when
+        // a chain through interior pointers is bettered by one through start pointers
+        let mut kinds: BTreeMap<usize, LeakKind> = blocks
+            .keys()
+            .map(|addr| (*addr, LeakKind::DefinitelyLost))
+            .collect();
+        let mut worklist = Vec::new();
+        for root in roots {
+            mark(
+                blocks,
+                &mut kinds,
+                &mut worklist,
+                *root,
+                LeakKind::StillReachable,
+            );
+        }
+        let scan_len = std::cmp::min(memory.len(), self.metadata.len());
+        let mut cur = 0;
+        for (start, len) in blocks.iter().map(|(a, l)| (*a, *l)).chain([(scan_len, 0)]) {
+            let end = start.min(scan_len);
+            if cur < end {
+                self.scan_initialized_words(memory, cur, end - cur, |ptr| {
+                    mark(
+                        blocks,
+                        &mut kinds,
+                        &mut worklist,
+                        ptr,
+                        LeakKind::StillReachable,
+                    )
+                });
+            }
+            cur = cur.max(start + len);
+        }
+        while let Some(start) = worklist.pop() {
+            let len = std::cmp::min(blocks[&start], scan_len.saturating_sub(start));
+            let mut found = Vec::new();
+            self.scan_initialized_words(memory, start, len, |ptr| found.push(ptr));
+            let via = kinds[&start];
+            for ptr in found {
+                mark(blocks, &mut kinds, &mut worklist, ptr, via);
+            }
+        }
+
+        let blocks = blocks
+            .iter()
+            .map(|(addr, len)| LeakedBlock {
+                addr: *addr,
+                len: *len,
+                kind: kinds[addr],
+                alloc_stack: self.alloc_stacks.get(addr).cloned().unwrap_or_default(),
+            })
+            .collect();
+        LeakReport { blocks }
+    }
+    fn scan_initialized_words(
+        &self,
+        memory: &[u8],
+        addr: usize,
+        len: usize,
+        mut f: impl FnMut(usize),
+    ) {
+        let mut cur = addr;
+        self.metadata.for_each_run(addr, len, |state, run_len| {
+            if state == MemState::ValidToReadWrite {
+                let end = cur + run_len;
+                let mut word = cur.next_multiple_of(POINTER_SIZE);
+                while word + POINTER_SIZE <= end {
+                    let bytes = memory[word..word + POINTER_SIZE].try_into().unwrap();
+                    f(u32::from_le_bytes(bytes) as usize);
+                    word += POINTER_SIZE;
+                }
+            }
+            cur += run_len;
+        });
+    }
+}
+
+/// Records that `ptr` is reached through a chain of kind `via`, queueing its block for a
+/// rescan when that improves on the best chain found so far.
+fn mark(
+    blocks: &BTreeMap<usize, usize>,
+    kinds: &mut BTreeMap<usize, LeakKind>,
+    worklist: &mut Vec<usize>,
+    ptr: usize,
+    via: LeakKind,
+) {
+    if let Some(start) = containing_block(blocks, ptr) {
+        let kind = if ptr == start {
+            via
+        } else {
+            LeakKind::PossiblyLost
+        };
+        let best = kinds.get_mut(&start).unwrap();
+        if rank(kind) > rank(*best) {
+            *best = kind;
+            worklist.push(start);
+        }
+    }
+}
+
+fn rank(kind: LeakKind) -> u8 {
+    match kind {
+        LeakKind::DefinitelyLost => 0,
+        LeakKind::PossiblyLost => 1,
+        LeakKind::StillReachable => 2,
+    }
+}
+
+fn containing_block(blocks: &BTreeMap<usize, usize>, ptr: usize) -> Option<usize> {
+    let (start, len) = blocks.range(..=ptr).next_back()?;
+    if ptr < start + len || ptr == *start {
+        Some(*start)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+fn store_ptr(memory: &mut [u8], addr: usize, ptr: usize) {
+    memory[addr..addr + 4].copy_from_slice(&(ptr as u32).to_le_bytes());
+}
+
+#[test]
+fn unreferenced_block_is_lost() {
+    let mut valgrind_state = Valgrind::new(64 * 1024, 1024);
+    let memory = vec![0; 64 * 1024];
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.malloc(0x2000, 16).is_ok());
+    assert!(valgrind_state.free(0x2000).is_ok());
+    let report = valgrind_state.leak_check(&memory, &[]);
+    assert_eq!(
+        report.blocks,
+        vec![LeakedBlock {
+            addr: 0x1000,
+            len: 32,
+            kind: LeakKind::DefinitelyLost,
+            alloc_stack: Box::default(),
+        }]
+    );
+    assert_eq!(
+        report.definitely_lost(),
+        LeakTotal {
+            blocks: 1,
+            bytes: 32
+        }
+    );
+    assert!(!report.is_leak_free());
+}
+
+#[test]
+fn reachable_through_stack_and_chain() {
+    let mut valgrind_state = Valgrind::new(64 * 1024, 1024);
+    let mut memory = vec![0; 64 * 1024];
+
+    assert!(valgrind_state.update_stack_pointer(1000).is_ok());
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.malloc(0x2000, 16).is_ok());
+    assert!(valgrind_state.malloc(0x3000, 8).is_ok());
+    // stack -> 0x1000 -> 0x2000; nothing points at 0x3000
+    store_ptr(&mut memory, 1004, 0x1000);
+    assert!(valgrind_state.write(1004, 4).is_ok());
+    store_ptr(&mut memory, 0x1008, 0x2000);
+    assert!(valgrind_state.write(0x1008, 4).is_ok());
+    let report = valgrind_state.leak_check(&memory, &[]);
+    assert_eq!(
+        report.still_reachable(),
+        LeakTotal {
+            blocks: 2,
+            bytes: 48
+        }
+    );
+    assert_eq!(
+        report.definitely_lost(),
+        LeakTotal {
+            blocks: 1,
+            bytes: 8
+        }
+    );
+    assert_eq!(report.blocks[2].kind, LeakKind::DefinitelyLost);
+}
+
+#[test]
+fn interior_pointers_are_possibly_lost() {
+    let mut valgrind_state = Valgrind::new(64 * 1024, 1024);
+    let mut memory = vec![0; 64 * 1024];
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.malloc(0x2000, 16).is_ok());
+    assert!(valgrind_state.malloc(0x3000, 8).is_ok());
+    // a global -> interior of 0x1000 -> 0x2000, and later straight to 0x3000
+    store_ptr(&mut memory, 0x1008, 0x2000);
+    assert!(valgrind_state.write(0x1008, 4).is_ok());
+    let report = valgrind_state.leak_check(&memory, &[0x1010, 0x3000]);
+    assert_eq!(
+        report.possibly_lost(),
+        LeakTotal {
+            blocks: 2,
+            bytes: 48
+        }
+    );
+    assert_eq!(report.blocks[2].kind, LeakKind::StillReachable);
+    assert!(!report.is_leak_free());
+
+    // a start pointer found later upgrades the whole chain
+    let report = valgrind_state.leak_check(&memory, &[0x1010, 0x3000, 0x1000]);
+    assert_eq!(report.possibly_lost().blocks, 0);
+    assert!(report.is_leak_free());
+}
+
+#[test]
+fn uninitialized_words_are_not_roots() {
+    let mut valgrind_state = Valgrind::new(64 * 1024, 1024);
+    let mut memory = vec![0; 64 * 1024];
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.malloc(0x2000, 16).is_ok());
+    store_ptr(&mut memory, 0x1000, 0x2000);
+    let report = valgrind_state.leak_check(&memory, &[0x1000]);
+    assert_eq!(
+        report.still_reachable(),
+        LeakTotal {
+            blocks: 1,
+            bytes: 32
+        }
+    );
+    assert_eq!(
+        report.definitely_lost(),
+        LeakTotal {
+            blocks: 1,
+            bytes: 16
+        }
+    );
+}
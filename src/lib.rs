@@ -2,161 +2,669 @@
 The following implementation assumes that the stack sits at the bottom of memory.
 */
 
-use std::cmp::*;
-use std::collections::HashMap;
+pub mod dwarf;
+mod error;
+mod freed;
+pub mod instrument;
+mod leak;
+mod log;
+mod monitor;
+mod origin;
+pub mod report;
+mod shadow;
+mod stack;
+mod suppress;
+mod undefined;
+#[cfg(feature = "wasmtime")]
+pub mod wasmtime;
+
+pub use error::{AccessError, Block, BlockPosition};
+pub use leak::{LeakKind, LeakReport, LeakTotal, LeakedBlock};
+pub use log::{ErrorLog, ErrorSummary, LoggedError};
+pub use monitor::Monitor;
+pub use origin::{Origin, OriginKind};
+pub use stack::StackFrame;
+pub use suppress::{SuppressionError, Suppressions};
+pub use undefined::{UndefinedChecks, UsePoint, UsePoints, ValueUse};
+
+use freed::{FreedRanges, Quarantine};
+use instrument::Symbols;
+use origin::Origins;
+use shadow::ShadowMemory;
+use stack::StackFrames;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, HashMap};
+
+pub const WASM_PAGE_SIZE: usize = 64 * 1024;
+const MAX_WASM32_PAGES: usize = 65536;
+const DEFAULT_STACK_DEPTH: usize = 12;
 
 pub struct Valgrind {
-    metadata: Vec<MemState>,
-    mallocs: HashMap<usize, usize>, // start addr, len
+    metadata: ShadowMemory,
+    mallocs: BTreeMap<usize, usize>, // start addr, len
+    freed: FreedRanges,
+    quarantine: Option<Quarantine>,
+    redzones: (usize, usize), // bytes before, bytes after each block
     stack_pointer: usize,
     max_stack_size: usize,
+    frames: StackFrames,
+    max_pages: usize,
+    log: Option<ErrorLog>, // continue-on-error mode
+    site: Option<usize>,
+    suppressions: Option<Suppressions>,
+    symbols: Symbols,                         // names the function of a site
+    call_stack: Vec<u32>, // functions entered and not yet exited, outermost first
+    stack_depth: usize,   // frames kept in each recorded stack
+    alloc_stacks: HashMap<usize, Box<[u32]>>, // live block addr -> stack of its allocation
+    undefined_checks: UndefinedChecks,
+    host_buffers: Vec<std::ops::Range<usize>>,
+    origins: Option<Origins>,
     //flag: bool,
 }
 
-#[derive(Debug, PartialEq)]
-pub enum AccessError {
-    DoubleMalloc { addr: usize, len: usize },
-    InvalidRead { addr: usize, len: usize },
-    InvalidWrite { addr: usize, len: usize },
-    InvalidFree { addr: usize },
-    OutOfBounds { addr: usize, len: usize },
-}
-
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MemState {
     Unallocated,
     ValidToWrite,
     ValidToReadWrite,
+    Freed,
+    Redzone,
 }
 
 impl Valgrind {
     pub fn new(mem_size: usize, max_stack_size: usize) -> Valgrind {
-        let metadata = vec![MemState::Unallocated; mem_size];
-        let mallocs = HashMap::new();
+        let metadata = ShadowMemory::new(mem_size, MemState::Unallocated);
+        let mallocs = BTreeMap::new();
         let stack_pointer = max_stack_size;
         Valgrind {
             metadata,
             mallocs,
+            freed: FreedRanges::default(),
+            quarantine: None,
+            redzones: (0, 0),
             stack_pointer,
             max_stack_size,
+            frames: StackFrames::default(),
+            max_pages: MAX_WASM32_PAGES,
+            log: None,
+            site: None,
+            suppressions: None,
+            symbols: Symbols::default(),
+            call_stack: Vec::new(),
+            stack_depth: DEFAULT_STACK_DEPTH,
+            alloc_stacks: HashMap::new(),
+            undefined_checks: UndefinedChecks::OnLoad,
+            host_buffers: Vec::new(),
+            origins: None,
         }
     }
+    /// Caps the number of wasm pages `grow` may extend memory to (default: the wasm32 limit).
+    pub fn set_max_pages(&mut self, max_pages: usize) {
+        self.max_pages = max_pages;
+    }
+    /// Keeps up to `bytes` of freed memory poisoned in a FIFO quarantine. Allocations that
+    /// reuse quarantined memory are reported; older frees are forgotten once the budget is
+    /// exceeded. Without a quarantine, freed memory may be reallocated immediately.
+    pub fn set_quarantine_size(&mut self, bytes: usize) {
+        self.quarantine = Some(Quarantine::new(bytes));
+    }
+    /// Tells `Valgrind` that the allocator keeps `before` header bytes and `after` padding
+    /// bytes around every block. Unallocated bytes there are marked as redzones, and touching
+    /// them is reported as a heap buffer overflow of the neighbouring block.
+    pub fn set_redzones(&mut self, before: usize, after: usize) {
+        self.redzones = (before, after);
+    }
+    /// In continue-on-error mode, checks record their errors in the error log and return
+    /// `Ok(())`, and the shadow state is still updated where that makes sense: the valid
+    /// bytes of an invalid write become initialized.
+    pub fn set_continue_on_error(&mut self, enabled: bool) {
+        self.log = enabled.then(|| self.log.take().unwrap_or_default());
+    }
+    /// The errors recorded so far in continue-on-error mode.
+    pub fn error_log(&self) -> Option<&ErrorLog> {
+        self.log.as_ref()
+    }
+    /// Sets the code location of the operations that follow, used to fold repeated errors.
+    pub fn set_site(&mut self, site: Option<usize>) {
+        self.site = site;
+    }
+    /// The code location of the current operation, as last set by `set_site`.
+    pub fn site(&self) -> Option<usize> {
+        self.site
+    }
+    /// Errors matching `suppressions` are dropped, both in continue-on-error mode and not.
+    pub fn set_suppressions(&mut self, suppressions: Suppressions) {
+        self.suppressions = Some(suppressions);
+    }
+    /// The suppressions in use, with the number of errors each one suppressed.
+    pub fn suppressions(&self) -> Option<&Suppressions> {
+        self.suppressions.as_ref()
+    }
+    /// Symbols of the module being checked, used to match suppressions by function name.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+    /// Number of innermost frames recorded at every allocation and free (default 12).
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack_depth = depth;
+    }
+    /// Follows a call of wasm function `func`; the calls in progress are recorded as the
+    /// allocation and free stacks of blocks.
+    pub fn enter_function(&mut self, func: u32) {
+        self.call_stack.push(func);
+    }
+    pub fn exit_function(&mut self) {
+        self.call_stack.pop();
+    }
+    /// The calls in progress, innermost first, cut to the configured depth.
+    pub fn call_stack(&self) -> Vec<u32> {
+        self.call_stack
+            .iter()
+            .rev()
+            .take(self.stack_depth)
+            .copied()
+            .collect()
+    }
+    /// Marks `addr..addr + len` as static data, which is always valid to read and write.
+    pub fn static_data(&mut self, addr: usize, len: usize) -> Result<(), AccessError> {
+        if !self.is_in_bounds(addr, len) {
+            return Err(AccessError::OutOfBounds { addr, len });
+        }
+        self.metadata
+            .set_range(addr, len, MemState::ValidToReadWrite);
+        Ok(())
+    }
+    /// Whether any byte of `addr..addr + len` is still held in the quarantine, i.e. whether
+    /// the host allocator should avoid handing it out.
+    pub fn is_quarantined(&self, addr: usize, len: usize) -> bool {
+        self.quarantine.is_some() && self.freed.overlapping(addr, len).is_some()
+    }
+    /// Models `memory.grow`: extends memory by `delta_pages` unallocated wasm pages and
+    /// returns the previous size in pages.
+    pub fn grow(&mut self, delta_pages: usize) -> Result<usize, AccessError> {
+        let cur_pages = self.metadata.len() / WASM_PAGE_SIZE;
+        // a request too large to count in pages or bytes is beyond any maximum
+        let new_len = cur_pages
+            .checked_add(delta_pages)
+            .filter(|pages| *pages <= self.max_pages)
+            .and_then(|_| delta_pages.checked_mul(WASM_PAGE_SIZE))
+            .and_then(|bytes| self.metadata.len().checked_add(bytes));
+        let Some(new_len) = new_len else {
+            return Err(AccessError::InvalidGrow {
+                cur_pages,
+                delta_pages,
+                max_pages: self.max_pages,
+            });
+        };
+        self.metadata.grow(new_len, MemState::Unallocated);
+        Ok(cur_pages)
+    }
     pub fn malloc(&mut self, addr: usize, len: usize) -> Result<(), AccessError> {
+        let result = self.allocate(addr, len, MemState::ValidToWrite);
+        self.report(result)
+    }
+    /// Models `calloc`: like `malloc`, but the block is zeroed and therefore readable.
+    pub fn calloc(&mut self, addr: usize, nmemb: usize, size: usize) -> Result<(), AccessError> {
+        let result = match nmemb.checked_mul(size) {
+            Some(len) => self.allocate(addr, len, MemState::ValidToReadWrite),
+            None => Err(AccessError::CallocOverflow { nmemb, size }),
+        };
+        self.report(result)
+    }
+    fn allocate(&mut self, addr: usize, len: usize, state: MemState) -> Result<(), AccessError> {
         if !self.is_in_bounds_heap(addr, len) {
+            return Err(AccessError::OutOfBounds { addr, len });
+        }
+        let allocated =
+            |state| matches!(state, MemState::ValidToWrite | MemState::ValidToReadWrite);
+        if self.metadata.find(addr, len, allocated).is_some() {
+            return Err(AccessError::DoubleMalloc { addr, len });
+        }
+        self.check_quarantine(addr, len)?;
+        self.metadata.set_range(addr, len, state);
+        let undefined = state == MemState::ValidToWrite;
+        self.record_origin(addr, len, undefined.then_some(OriginKind::Heap));
+        self.freed.remove(addr, len);
+        self.mallocs.insert(addr, len);
+        self.record_alloc_stack(addr);
+        self.mark_redzones(addr, len);
+        Ok(())
+    }
+    /// Models `realloc`: the block at `old_addr` is resized to `new_len` bytes at `new_addr`.
+    /// The shadow state of the preserved prefix moves with the block and any grown tail is
+    /// uninitialized.
+    pub fn realloc(
+        &mut self,
+        old_addr: usize,
+        new_addr: usize,
+        new_len: usize,
+    ) -> Result<(), AccessError> {
+        let result = self.resize(old_addr, new_addr, new_len);
+        self.report(result)
+    }
+    fn resize(
+        &mut self,
+        old_addr: usize,
+        new_addr: usize,
+        new_len: usize,
+    ) -> Result<(), AccessError> {
+        let old_len = match self.mallocs.get(&old_addr) {
+            Some(len) => *len,
+            None => {
+                return Err(AccessError::InvalidFree {
+                    addr: old_addr,
+                    block: self.nearest_block(old_addr),
+                    freed_block: self.freed.block_at(old_addr).cloned(),
+                })
+            }
+        };
+        if !self.is_in_bounds_heap(new_addr, new_len) {
             return Err(AccessError::OutOfBounds {
-                addr: addr,
-                len: len,
+                addr: new_addr,
+                len: new_len,
             });
         }
-        for i in addr..addr + len {
-            match self.metadata[i] {
-                MemState::ValidToWrite => {
-                    return Err(AccessError::DoubleMalloc {
-                        addr: addr,
-                        len: len,
-                    });
-                }
-                MemState::ValidToReadWrite => {
-                    return Err(AccessError::DoubleMalloc {
-                        addr: addr,
-                        len: len,
-                    });
+        // The new block may only overlap memory owned by the block being resized.
+        let allocated =
+            |state| matches!(state, MemState::ValidToWrite | MemState::ValidToReadWrite);
+        let new_end = new_addr + new_len;
+        let old_end = old_addr + old_len;
+        let outside_old = [
+            (new_addr, min(new_end, old_addr)),
+            (max(new_addr, old_end), new_end),
+        ];
+        for (start, end) in outside_old {
+            if start < end && self.metadata.find(start, end - start, allocated).is_some() {
+                return Err(AccessError::DoubleMalloc {
+                    addr: new_addr,
+                    len: new_len,
+                });
+            }
+        }
+        self.check_quarantine(new_addr, new_len)?;
+        let preserved = min(old_len, new_len);
+        let old_block = self.freed_block(old_addr, old_len);
+        if new_addr != old_addr {
+            self.metadata.copy_within(old_addr, new_addr, preserved);
+            self.copy_origins(old_addr, new_addr, preserved);
+            let (dst_start, dst_end) = (new_addr, new_addr + preserved);
+            for (start, end) in [
+                (old_addr, min(old_end, dst_start)),
+                (max(old_addr, dst_end), old_end),
+            ] {
+                if start < end {
+                    self.release(start, end - start, old_block.clone());
                 }
-                _ => {}
             }
+        } else if new_len < old_len {
+            self.release(new_end, old_len - new_len, old_block);
         }
-        for i in addr..addr + len {
-            self.metadata[i] = MemState::ValidToWrite;
+        self.freed.remove(new_addr, new_len);
+        if new_len > preserved {
+            self.metadata.set_range(
+                new_addr + preserved,
+                new_len - preserved,
+                MemState::ValidToWrite,
+            );
+            let grown = (new_addr + preserved, new_len - preserved);
+            self.record_origin(grown.0, grown.1, Some(OriginKind::Heap));
         }
-        self.mallocs.insert(addr, len);
+        self.mallocs.remove(&old_addr);
+        self.mallocs.insert(new_addr, new_len);
+        self.alloc_stacks.remove(&old_addr);
+        self.record_alloc_stack(new_addr);
+        self.clear_redzones(old_addr, old_len);
+        self.mark_redzones(new_addr, new_len);
         Ok(())
     }
     pub fn read(&mut self, addr: usize, len: usize) -> Result<(), AccessError> {
-        if !(self.is_in_bounds_stack(addr, len) || self.is_in_bounds_heap(addr, len)) {
-            return Err(AccessError::OutOfBounds {
-                addr: addr,
-                len: len,
-            });
+        let result = self.check_read(addr, len);
+        self.report(result)
+    }
+    fn check_read(&self, addr: usize, len: usize) -> Result<(), AccessError> {
+        if !self.is_in_bounds(addr, len) {
+            return Err(AccessError::OutOfBounds { addr, len });
         }
-        for i in addr..addr + len {
-            match self.metadata[i] {
-                MemState::Unallocated => {
-                    return Err(AccessError::InvalidRead {
-                        addr: addr,
-                        len: len,
-                    });
-                }
-                MemState::ValidToWrite => {
-                    return Err(AccessError::InvalidRead {
-                        addr: addr,
-                        len: len,
-                    });
-                }
-                _ => {}
-            }
+        if let Some(bad_addr) = self
+            .metadata
+            .find(addr, len, |state| !self.is_loadable(state))
+        {
+            return Err(self.access_error(addr, len, bad_addr, false));
         }
         Ok(())
     }
     pub fn write(&mut self, addr: usize, len: usize) -> Result<(), AccessError> {
-        if !(self.is_in_bounds_stack(addr, len) || self.is_in_bounds_heap(addr, len)) {
-            return Err(AccessError::OutOfBounds {
-                addr: addr,
-                len: len,
-            });
+        let result = self.check_write(addr, len);
+        if result.is_err() {
+            self.written_despite_error(addr, len);
         }
-        for i in addr..addr + len {
-            if let MemState::Unallocated = self.metadata[i] {
-                return Err(AccessError::InvalidWrite {
-                    addr: addr,
-                    len: len,
-                });
+        self.report(result)
+    }
+    /// Models `memory.fill`, and `memory.init` whose passive data is always initialized:
+    /// every byte of `dst..dst + len` is written.
+    pub fn fill(&mut self, dst: usize, len: usize) -> Result<(), AccessError> {
+        self.write(dst, len)
+    }
+    /// Models `memory.copy`: both ranges must be allocated, and each destination byte
+    /// takes the state of its source byte, so uninitialized bytes stay uninitialized.
+    /// The ranges may overlap.
+    pub fn copy(&mut self, dst: usize, src: usize, len: usize) -> Result<(), AccessError> {
+        let result = self.check_copy(dst, src, len);
+        if result.is_err() {
+            self.written_despite_error(dst, len);
+        }
+        self.report(result)
+    }
+    fn check_copy(&mut self, dst: usize, src: usize, len: usize) -> Result<(), AccessError> {
+        for addr in [src, dst] {
+            if !self.is_in_bounds(addr, len) {
+                return Err(AccessError::OutOfBounds { addr, len });
             }
         }
-        for i in addr..addr + len {
-            self.metadata[i] = MemState::ValidToReadWrite;
+        let unallocated = |state| {
+            matches!(
+                state,
+                MemState::Unallocated | MemState::Freed | MemState::Redzone
+            )
+        };
+        if let Some(bad_addr) = self.metadata.find(src, len, unallocated) {
+            return Err(self.access_error(src, len, bad_addr, false));
+        }
+        if let Some(bad_addr) = self.metadata.find(dst, len, unallocated) {
+            return Err(self.access_error(dst, len, bad_addr, true));
         }
+        self.check_copy_to_host(dst, src, len)?;
+        self.metadata.copy_within(src, dst, len);
+        self.copy_origins(src, dst, len);
+        Ok(())
+    }
+    /// In continue-on-error mode the guest carries on after a bad write, so the bytes it
+    /// could write become initialized rather than causing further errors.
+    fn written_despite_error(&mut self, addr: usize, len: usize) {
+        if self.log.is_some() && addr < self.metadata.len() {
+            let len = min(len, self.metadata.len() - addr);
+            let writable = |state| state == MemState::ValidToWrite;
+            self.metadata
+                .replace(addr, len, writable, MemState::ValidToReadWrite);
+        }
+    }
+    fn check_write(&mut self, addr: usize, len: usize) -> Result<(), AccessError> {
+        if !self.is_in_bounds(addr, len) {
+            return Err(AccessError::OutOfBounds { addr, len });
+        }
+        let unwritable = |state| {
+            matches!(
+                state,
+                MemState::Unallocated | MemState::Freed | MemState::Redzone
+            )
+        };
+        if let Some(bad_addr) = self.metadata.find(addr, len, unwritable) {
+            return Err(self.access_error(addr, len, bad_addr, true));
+        }
+        self.metadata
+            .set_range(addr, len, MemState::ValidToReadWrite);
         Ok(())
     }
     pub fn free(&mut self, addr: usize) -> Result<(), AccessError> {
+        let result = self.deallocate(addr);
+        self.report(result)
+    }
+    fn deallocate(&mut self, addr: usize) -> Result<(), AccessError> {
         if !self.mallocs.contains_key(&addr) {
-            return Err(AccessError::InvalidFree { addr: addr });
+            return Err(AccessError::InvalidFree {
+                addr,
+                block: self.nearest_block(addr),
+                freed_block: self.freed.block_at(addr).cloned(),
+            });
         }
         let len = self.mallocs[&addr];
-        for i in addr..addr + len {
-            if let MemState::Unallocated = self.metadata[i] {
-                return Err(AccessError::InvalidFree { addr: addr });
-            }
+        let unallocated = |state| matches!(state, MemState::Unallocated | MemState::Freed);
+        if self.metadata.find(addr, len, unallocated).is_some() {
+            return Err(AccessError::InvalidFree {
+                addr,
+                block: self.nearest_block(addr),
+                freed_block: None,
+            });
         }
+        let block = self.freed_block(addr, len);
         self.mallocs.remove(&addr);
-        for i in addr..addr + len {
-            self.metadata[i] = MemState::Unallocated;
-        }
+        self.alloc_stacks.remove(&addr);
+        self.release(addr, len, block);
+        self.clear_redzones(addr, len);
         Ok(())
     }
-    fn is_in_bounds_heap(&self, addr: usize, len: usize) -> bool {
-        self.max_stack_size <= addr && addr + len <= self.metadata.len()
+    fn record_alloc_stack(&mut self, addr: usize) {
+        let stack = self.call_stack();
+        if stack.is_empty() {
+            self.alloc_stacks.remove(&addr);
+        } else {
+            self.alloc_stacks.insert(addr, stack.into());
+        }
     }
-    fn is_in_bounds_stack(&self, addr: usize, len: usize) -> bool {
-        self.stack_pointer <= addr && addr + len < self.max_stack_size
+    /// The live block at `addr`, with its allocation stack.
+    fn live_block(&self, addr: usize, len: usize) -> Block {
+        let mut block = Block::new(addr, len);
+        if let Some(stack) = self.alloc_stacks.get(&addr) {
+            block.alloc_stack = stack.clone();
+        }
+        block
     }
-    pub fn update_stack_pointer(&mut self, new_sp: usize) -> Result<(), AccessError> {
-        if new_sp > self.max_stack_size {
-            return Err(AccessError::OutOfBounds {
-                addr: self.stack_pointer,
-                len: new_sp - self.stack_pointer,
-            });
-        } else if new_sp < self.stack_pointer {
-            for i in new_sp..self.stack_pointer + 1 {
-                // +1 to account for sp == max_stack_size (?)
-                self.metadata[i] = MemState::ValidToReadWrite;
+    /// The live block at `addr` as it is being freed by the current call stack.
+    fn freed_block(&self, addr: usize, len: usize) -> Block {
+        let mut block = self.live_block(addr, len);
+        block.free_stack = self.call_stack().into();
+        block
+    }
+    /// Marks `addr..addr + len`, which belonged to `block`, as freed.
+    fn release(&mut self, addr: usize, len: usize, block: Block) {
+        self.metadata.set_range(addr, len, MemState::Freed);
+        self.freed.insert(addr, len, block);
+        let mut evicted = Vec::new();
+        if let Some(quarantine) = &mut self.quarantine {
+            quarantine.push(addr, len);
+            while let Some(range) = quarantine.evict() {
+                evicted.push(range);
+            }
+        }
+        for (addr, len) in evicted {
+            self.freed.remove(addr, len);
+            self.metadata.set_range(addr, len, MemState::Unallocated);
+            self.clear_redzones(addr, len);
+        }
+    }
+    /// Poisons the unallocated bytes around the block at `addr` as redzones.
+    fn mark_redzones(&mut self, addr: usize, len: usize) {
+        let (before, after) = self.redzones;
+        let start = max(addr.saturating_sub(before), self.max_stack_size);
+        let end = min(addr + len + after, self.metadata.len());
+        let unallocated = |state| state == MemState::Unallocated;
+        if start < addr {
+            self.metadata
+                .replace(start, addr - start, unallocated, MemState::Redzone);
+        }
+        if addr + len < end {
+            self.metadata
+                .replace(addr + len, end - addr - len, unallocated, MemState::Redzone);
+        }
+    }
+    /// Unpoisons the redzones around `addr..addr + len`, keeping those that still belong to
+    /// the neighbouring live blocks.
+    fn clear_redzones(&mut self, addr: usize, len: usize) {
+        let (before, after) = self.redzones;
+        if before == 0 && after == 0 {
+            return;
+        }
+        let start = max(addr.saturating_sub(before), self.max_stack_size);
+        let end = min(addr + len + after, self.metadata.len());
+        let redzone = |state| state == MemState::Redzone;
+        self.metadata
+            .replace(start, end - start, redzone, MemState::Unallocated);
+        let neighbours: Vec<(usize, usize)> = self
+            .mallocs
+            .range(..addr)
+            .next_back()
+            .into_iter()
+            .chain(self.mallocs.range(addr..).next())
+            .map(|(addr, len)| (*addr, *len))
+            .collect();
+        for (addr, len) in neighbours {
+            self.mark_redzones(addr, len);
+        }
+    }
+    /// Finds the live block whose redzone contains `addr`, preferring an overflow of the
+    /// block below over an underflow of the block above.
+    fn redzone_owner(&self, addr: usize) -> Option<(Block, BlockPosition)> {
+        let (before, after) = self.redzones;
+        if let Some((start, len)) = self.mallocs.range(..=addr).next_back() {
+            let block = self.live_block(*start, *len);
+            if let BlockPosition::After { distance } = block.position(addr) {
+                if distance < after {
+                    return Some((block, BlockPosition::After { distance }));
+                }
+            }
+        }
+        let (start, len) = self.mallocs.range(addr + 1..).next()?;
+        let block = self.live_block(*start, *len);
+        match block.position(addr) {
+            BlockPosition::Before { distance } if distance <= before => {
+                Some((block, BlockPosition::Before { distance }))
+            }
+            _ => None,
+        }
+    }
+    /// Drops suppressed errors, and records `result`'s error instead of returning it in
+    /// continue-on-error mode.
+    fn report(&mut self, result: Result<(), AccessError>) -> Result<(), AccessError> {
+        let err = match result {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        // without calls reported, the function of the site is the only known frame
+        let mut stack = self.call_stack();
+        if stack.is_empty() {
+            stack.extend(self.site.and_then(|site| self.symbols.function_at(site)));
+        }
+        if let Some(suppressions) = &mut self.suppressions {
+            let frames = stack
+                .iter()
+                .map(|func| self.symbols.name(*func).unwrap_or("???"))
+                .collect::<Vec<_>>();
+            if suppressions.suppress(&err, &frames) {
+                return Ok(());
+            }
+        }
+        match &mut self.log {
+            Some(log) => {
+                log.record(err, self.site, stack);
+                Ok(())
+            }
+            None => Err(err),
+        }
+    }
+    fn check_quarantine(&self, addr: usize, len: usize) -> Result<(), AccessError> {
+        if self.quarantine.is_none() {
+            return Ok(());
+        }
+        match self.freed.overlapping(addr, len) {
+            Some(freed_block) => Err(AccessError::QuarantinedMalloc {
+                addr,
+                len,
+                freed_block: freed_block.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+    fn access_error(
+        &self,
+        addr: usize,
+        len: usize,
+        bad_addr: usize,
+        is_write: bool,
+    ) -> AccessError {
+        let state = self.metadata.get(bad_addr);
+        if state == MemState::Freed {
+            if let Some(freed_block) = self.freed.block_at(bad_addr) {
+                return AccessError::UseAfterFree {
+                    addr,
+                    len,
+                    freed_block: freed_block.clone(),
+                };
+            }
+        }
+        if state == MemState::Redzone {
+            if let Some((block, position)) = self.redzone_owner(bad_addr) {
+                return AccessError::HeapBufferOverflow {
+                    addr,
+                    len,
+                    bad_addr,
+                    block,
+                    position,
+                };
+            }
+        }
+        if state == MemState::Unallocated {
+            if let Some(frame) = self.frames.returned_at(bad_addr) {
+                return AccessError::UseAfterReturn {
+                    addr,
+                    len,
+                    frame: frame.clone(),
+                };
+            }
+        }
+        let block = self.nearest_block(bad_addr);
+        if is_write {
+            AccessError::InvalidWrite {
+                addr,
+                len,
+                bad_addr,
+                state,
+                block,
             }
         } else {
-            for i in self.stack_pointer..new_sp {
-                self.metadata[i] = MemState::Unallocated;
+            // memcheck only tracks where undefined bytes came from
+            let origin = (state == MemState::ValidToWrite)
+                .then(|| self.origin_at(bad_addr))
+                .flatten();
+            AccessError::InvalidRead {
+                addr,
+                len,
+                bad_addr,
+                state,
+                block,
+                origin,
             }
         }
-        self.stack_pointer = new_sp;
-        Ok(())
+    }
+    /// Returns the live block containing `addr`, or else the closest one on either side.
+    fn nearest_block(&self, addr: usize) -> Option<Block> {
+        let below = self.mallocs.range(..=addr).next_back();
+        if let Some((start, len)) = below {
+            if addr < start + len {
+                return Some(self.live_block(*start, *len));
+            }
+        }
+        let above = self.mallocs.range(addr + 1..).next();
+        match (below, above) {
+            (Some((b_start, b_len)), Some((a_start, a_len))) => {
+                if addr - (b_start + b_len) <= a_start - addr {
+                    Some(self.live_block(*b_start, *b_len))
+                } else {
+                    Some(self.live_block(*a_start, *a_len))
+                }
+            }
+            (Some((start, len)), None) | (None, Some((start, len))) => {
+                Some(self.live_block(*start, *len))
+            }
+            (None, None) => None,
+        }
+    }
+    fn is_in_bounds_heap(&self, addr: usize, len: usize) -> bool {
+        self.max_stack_size <= addr && addr + len <= self.metadata.len()
+    }
+    /// Reads and writes are bounded by linear memory alone: below the stack pointer the
+    /// shadow state tells static data, returned frames and never used stack apart.
+    fn is_in_bounds(&self, addr: usize, len: usize) -> bool {
+        addr + len <= self.metadata.len()
     }
 }
 
@@ -167,7 +675,7 @@ fn basic_valgrind() {
     assert!(valgrind_state.malloc(0x1000, 32).is_ok());
     assert!(valgrind_state.write(0x1000, 4).is_ok());
     assert!(valgrind_state.read(0x1000, 4).is_ok());
-    assert_eq!(valgrind_state.mallocs, HashMap::from([(0x1000, 32)]));
+    assert_eq!(valgrind_state.mallocs, BTreeMap::from([(0x1000, 32)]));
     assert!(valgrind_state.free(0x1000).is_ok());
     assert!(valgrind_state.mallocs.is_empty());
 }
@@ -181,7 +689,11 @@ fn read_before_initializing() {
         valgrind_state.read(0x1000, 4),
         Err(AccessError::InvalidRead {
             addr: 0x1000,
-            len: 4
+            len: 4,
+            bad_addr: 0x1000,
+            state: MemState::ValidToWrite,
+            block: Some(Block::new(0x1000, 32)),
+            origin: None,
         })
     );
     assert!(valgrind_state.write(0x1000, 4).is_ok());
@@ -198,9 +710,56 @@ fn use_after_free() {
     assert!(valgrind_state.free(0x1000).is_ok());
     assert_eq!(
         valgrind_state.write(0x1000, 4),
-        Err(AccessError::InvalidWrite {
+        Err(AccessError::UseAfterFree {
             addr: 0x1000,
-            len: 4
+            len: 4,
+            freed_block: Block::new(0x1000, 32)
+        })
+    );
+}
+
+#[test]
+fn freed_vs_never_allocated() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.free(0x1000).is_ok());
+    assert_eq!(
+        valgrind_state.read(0x1010, 32),
+        Err(AccessError::UseAfterFree {
+            addr: 0x1010,
+            len: 32,
+            freed_block: Block::new(0x1000, 32)
+        })
+    );
+    assert_eq!(
+        valgrind_state.read(0x1020, 4),
+        Err(AccessError::InvalidRead {
+            addr: 0x1020,
+            len: 4,
+            bad_addr: 0x1020,
+            state: MemState::Unallocated,
+            block: None,
+            origin: None,
+        })
+    );
+    assert!(valgrind_state.malloc(0x1000, 16).is_ok());
+    assert!(valgrind_state.write(0x1000, 16).is_ok());
+    assert_eq!(
+        valgrind_state.write(0x1000, 20),
+        Err(AccessError::UseAfterFree {
+            addr: 0x1000,
+            len: 20,
+            freed_block: Block::new(0x1000, 32)
+        })
+    );
+    assert!(valgrind_state.free(0x1000).is_ok());
+    assert_eq!(
+        valgrind_state.read(0x1000, 4),
+        Err(AccessError::UseAfterFree {
+            addr: 0x1000,
+            len: 4,
+            freed_block: Block::new(0x1000, 16)
         })
     );
 }
@@ -214,7 +773,11 @@ fn double_free() {
     assert!(valgrind_state.free(0x1000).is_ok());
     assert_eq!(
         valgrind_state.free(0x1000),
-        Err(AccessError::InvalidFree { addr: 0x1000 })
+        Err(AccessError::InvalidFree {
+            addr: 0x1000,
+            block: None,
+            freed_block: Some(Block::new(0x1000, 32))
+        })
     );
 }
 
@@ -338,11 +901,24 @@ fn bad_stack_read_write() {
     assert_eq!(valgrind_state.stack_pointer, 512);
     assert_eq!(
         valgrind_state.read(256, 16),
-        Err(AccessError::InvalidRead { addr: 256, len: 16 })
+        Err(AccessError::InvalidRead {
+            addr: 256,
+            len: 16,
+            bad_addr: 256,
+            state: MemState::Unallocated,
+            block: None,
+            origin: None,
+        })
     );
     assert_eq!(
         valgrind_state.write(500, 32),
-        Err(AccessError::InvalidWrite { addr: 500, len: 32 })
+        Err(AccessError::InvalidWrite {
+            addr: 500,
+            len: 32,
+            bad_addr: 500,
+            state: MemState::Unallocated,
+            block: None
+        })
     );
 }
 
@@ -356,6 +932,398 @@ fn stack_full_empty() {
     assert_eq!(valgrind_state.stack_pointer, 1024)
 }
 
+#[test]
+fn grow_extends_heap() {
+    let mut valgrind_state = Valgrind::new(2 * WASM_PAGE_SIZE, 1024);
+
+    assert_eq!(
+        valgrind_state.malloc(2 * WASM_PAGE_SIZE, 32),
+        Err(AccessError::OutOfBounds {
+            addr: 2 * WASM_PAGE_SIZE,
+            len: 32
+        })
+    );
+    assert_eq!(valgrind_state.grow(1), Ok(2));
+    assert!(valgrind_state.malloc(2 * WASM_PAGE_SIZE, 32).is_ok());
+    assert!(valgrind_state.write(2 * WASM_PAGE_SIZE, 32).is_ok());
+    assert!(valgrind_state.read(2 * WASM_PAGE_SIZE, 32).is_ok());
+    assert_eq!(
+        valgrind_state.read(3 * WASM_PAGE_SIZE - 4, 8),
+        Err(AccessError::OutOfBounds {
+            addr: 3 * WASM_PAGE_SIZE - 4,
+            len: 8
+        })
+    );
+    assert_eq!(valgrind_state.grow(0), Ok(3));
+}
+
+#[test]
+fn grow_by_huge_deltas() {
+    let mut valgrind_state = Valgrind::new(2 * WASM_PAGE_SIZE, 1024);
+
+    assert_eq!(
+        valgrind_state.grow(usize::MAX),
+        Err(AccessError::InvalidGrow {
+            cur_pages: 2,
+            delta_pages: usize::MAX,
+            max_pages: MAX_WASM32_PAGES
+        })
+    );
+    // without a maximum, the byte count overflows instead
+    valgrind_state.set_max_pages(usize::MAX);
+    for delta_pages in [usize::MAX - 2, usize::MAX / WASM_PAGE_SIZE] {
+        assert!(matches!(
+            valgrind_state.grow(delta_pages),
+            Err(AccessError::InvalidGrow { .. })
+        ));
+    }
+    assert_eq!(valgrind_state.grow(0), Ok(2));
+}
+
+#[test]
+fn grow_past_max() {
+    let mut valgrind_state = Valgrind::new(2 * WASM_PAGE_SIZE, 1024);
+    valgrind_state.set_max_pages(4);
+
+    assert_eq!(valgrind_state.grow(2), Ok(2));
+    assert_eq!(
+        valgrind_state.grow(1),
+        Err(AccessError::InvalidGrow {
+            cur_pages: 4,
+            delta_pages: 1,
+            max_pages: 4
+        })
+    );
+    assert_eq!(
+        valgrind_state.malloc(4 * WASM_PAGE_SIZE, 1),
+        Err(AccessError::OutOfBounds {
+            addr: 4 * WASM_PAGE_SIZE,
+            len: 1
+        })
+    );
+}
+
+#[test]
+fn realloc_grow_in_place() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0x1000, 16).is_ok());
+    assert!(valgrind_state.write(0x1000, 8).is_ok());
+    assert!(valgrind_state.realloc(0x1000, 0x1000, 32).is_ok());
+    assert_eq!(valgrind_state.mallocs, BTreeMap::from([(0x1000, 32)]));
+    assert!(valgrind_state.read(0x1000, 8).is_ok());
+    assert_eq!(
+        valgrind_state.read(0x1008, 4),
+        Err(AccessError::InvalidRead {
+            addr: 0x1008,
+            len: 4,
+            bad_addr: 0x1008,
+            state: MemState::ValidToWrite,
+            block: Some(Block::new(0x1000, 32)),
+            origin: None,
+        })
+    );
+    assert!(valgrind_state.write(0x1010, 16).is_ok());
+    assert!(valgrind_state.free(0x1000).is_ok());
+}
+
+#[test]
+fn realloc_move() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0x1000, 16).is_ok());
+    assert!(valgrind_state.write(0x1000, 4).is_ok());
+    assert!(valgrind_state.realloc(0x1000, 0x2000, 64).is_ok());
+    assert_eq!(valgrind_state.mallocs, BTreeMap::from([(0x2000, 64)]));
+    assert!(valgrind_state.read(0x2000, 4).is_ok());
+    assert_eq!(
+        valgrind_state.read(0x2004, 4),
+        Err(AccessError::InvalidRead {
+            addr: 0x2004,
+            len: 4,
+            bad_addr: 0x2004,
+            state: MemState::ValidToWrite,
+            block: Some(Block::new(0x2000, 64)),
+            origin: None,
+        })
+    );
+    assert_eq!(
+        valgrind_state.write(0x1000, 4),
+        Err(AccessError::UseAfterFree {
+            addr: 0x1000,
+            len: 4,
+            freed_block: Block::new(0x1000, 16)
+        })
+    );
+    assert_eq!(
+        valgrind_state.free(0x1000),
+        Err(AccessError::InvalidFree {
+            addr: 0x1000,
+            block: Some(Block::new(0x2000, 64)),
+            freed_block: Some(Block::new(0x1000, 16))
+        })
+    );
+    assert!(valgrind_state.free(0x2000).is_ok());
+}
+
+#[test]
+fn realloc_shrink_and_overlapping_move() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.write(0x1000, 32).is_ok());
+    assert!(valgrind_state.realloc(0x1000, 0x1000, 8).is_ok());
+    assert_eq!(
+        valgrind_state.write(0x1008, 4),
+        Err(AccessError::UseAfterFree {
+            addr: 0x1008,
+            len: 4,
+            freed_block: Block::new(0x1000, 32)
+        })
+    );
+    assert!(valgrind_state.realloc(0x1000, 0x1004, 8).is_ok());
+    assert!(valgrind_state.read(0x1004, 8).is_ok());
+    assert_eq!(
+        valgrind_state.read(0x1000, 4),
+        Err(AccessError::UseAfterFree {
+            addr: 0x1000,
+            len: 4,
+            freed_block: Block::new(0x1000, 8)
+        })
+    );
+}
+
+#[test]
+fn bad_realloc() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.malloc(0x1000, 16).is_ok());
+    assert!(valgrind_state.malloc(0x1020, 16).is_ok());
+    assert_eq!(
+        valgrind_state.realloc(0x1010, 0x2000, 16),
+        Err(AccessError::InvalidFree {
+            addr: 0x1010,
+            block: Some(Block::new(0x1000, 16)),
+            freed_block: None
+        })
+    );
+    assert_eq!(
+        valgrind_state.realloc(0x1000, 0x1000, 48),
+        Err(AccessError::DoubleMalloc {
+            addr: 0x1000,
+            len: 48
+        })
+    );
+    assert_eq!(
+        valgrind_state.realloc(0x1000, 640 * 1024 - 8, 16),
+        Err(AccessError::OutOfBounds {
+            addr: 640 * 1024 - 8,
+            len: 16
+        })
+    );
+    assert_eq!(
+        valgrind_state.mallocs,
+        BTreeMap::from([(0x1000, 16), (0x1020, 16)])
+    );
+}
+
+#[test]
+fn calloc_is_initialized() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+
+    assert!(valgrind_state.calloc(0x1000, 8, 4).is_ok());
+    assert_eq!(valgrind_state.mallocs, BTreeMap::from([(0x1000, 32)]));
+    assert!(valgrind_state.read(0x1000, 32).is_ok());
+    assert_eq!(
+        valgrind_state.read(0x1000, 33),
+        Err(AccessError::InvalidRead {
+            addr: 0x1000,
+            len: 33,
+            bad_addr: 0x1020,
+            state: MemState::Unallocated,
+            block: Some(Block::new(0x1000, 32)),
+            origin: None,
+        })
+    );
+    assert_eq!(
+        valgrind_state.calloc(0x1010, 1, 4),
+        Err(AccessError::DoubleMalloc {
+            addr: 0x1010,
+            len: 4
+        })
+    );
+    assert!(valgrind_state.free(0x1000).is_ok());
+}
+
+#[test]
+fn calloc_overflow() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+
+    assert_eq!(
+        valgrind_state.calloc(0x1000, usize::MAX / 2, 4),
+        Err(AccessError::CallocOverflow {
+            nmemb: usize::MAX / 2,
+            size: 4
+        })
+    );
+    assert!(valgrind_state.mallocs.is_empty());
+}
+
+#[test]
+fn quarantine_catches_reuse() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+    valgrind_state.set_quarantine_size(64);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.free(0x1000).is_ok());
+    assert!(valgrind_state.is_quarantined(0x0ff0, 0x20));
+    assert!(!valgrind_state.is_quarantined(0x1020, 0x20));
+    assert_eq!(
+        valgrind_state.malloc(0x1010, 32),
+        Err(AccessError::QuarantinedMalloc {
+            addr: 0x1010,
+            len: 32,
+            freed_block: Block::new(0x1000, 32)
+        })
+    );
+    assert!(valgrind_state.mallocs.is_empty());
+    assert_eq!(
+        valgrind_state.read(0x1000, 4),
+        Err(AccessError::UseAfterFree {
+            addr: 0x1000,
+            len: 4,
+            freed_block: Block::new(0x1000, 32)
+        })
+    );
+}
+
+#[test]
+fn quarantine_evicts_by_budget() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+    valgrind_state.set_quarantine_size(48);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.malloc(0x2000, 32).is_ok());
+    assert!(valgrind_state.free(0x1000).is_ok());
+    assert!(valgrind_state.free(0x2000).is_ok());
+    assert!(!valgrind_state.is_quarantined(0x1000, 32));
+    assert!(valgrind_state.is_quarantined(0x2000, 32));
+    assert_eq!(
+        valgrind_state.read(0x1000, 4),
+        Err(AccessError::InvalidRead {
+            addr: 0x1000,
+            len: 4,
+            bad_addr: 0x1000,
+            state: MemState::Unallocated,
+            block: None,
+            origin: None,
+        })
+    );
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert_eq!(
+        valgrind_state.realloc(0x1000, 0x2000, 32),
+        Err(AccessError::QuarantinedMalloc {
+            addr: 0x2000,
+            len: 32,
+            freed_block: Block::new(0x2000, 32)
+        })
+    );
+}
+
+#[test]
+fn redzone_overflow_and_underflow() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+    valgrind_state.set_redzones(8, 16);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.write(0x1000, 32).is_ok());
+    assert_eq!(
+        valgrind_state.read(0x1010, 20),
+        Err(AccessError::HeapBufferOverflow {
+            addr: 0x1010,
+            len: 20,
+            bad_addr: 0x1020,
+            block: Block::new(0x1000, 32),
+            position: BlockPosition::After { distance: 0 }
+        })
+    );
+    assert_eq!(
+        valgrind_state.write(0x0ffc, 4),
+        Err(AccessError::HeapBufferOverflow {
+            addr: 0x0ffc,
+            len: 4,
+            bad_addr: 0x0ffc,
+            block: Block::new(0x1000, 32),
+            position: BlockPosition::Before { distance: 4 }
+        })
+    );
+    assert_eq!(
+        valgrind_state.write(0x1030, 4),
+        Err(AccessError::InvalidWrite {
+            addr: 0x1030,
+            len: 4,
+            bad_addr: 0x1030,
+            state: MemState::Unallocated,
+            block: Some(Block::new(0x1000, 32))
+        })
+    );
+}
+
+#[test]
+fn redzones_shared_between_neighbours() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 0);
+    valgrind_state.set_redzones(8, 8);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.malloc(0x1030, 16).is_ok());
+    assert!(valgrind_state.malloc(0x1024, 4).is_ok());
+    assert!(valgrind_state.free(0x1024).is_ok());
+    assert_eq!(
+        valgrind_state.write(0x102c, 1),
+        Err(AccessError::HeapBufferOverflow {
+            addr: 0x102c,
+            len: 1,
+            bad_addr: 0x102c,
+            block: Block::new(0x1030, 16),
+            position: BlockPosition::Before { distance: 4 }
+        })
+    );
+    assert!(valgrind_state.free(0x1000).is_ok());
+    assert!(valgrind_state.free(0x1030).is_ok());
+    assert_eq!(
+        valgrind_state.write(0x0ffc, 4),
+        Err(AccessError::InvalidWrite {
+            addr: 0x0ffc,
+            len: 4,
+            bad_addr: 0x0ffc,
+            state: MemState::Unallocated,
+            block: None
+        })
+    );
+    assert!(valgrind_state.malloc(0x1020, 16).is_ok());
+}
+
+#[test]
+fn large_memory_malloc_read_write() {
+    let mut valgrind_state = Valgrind::new(4 * 1024 * 1024 * 1024, 1024);
+
+    assert!(valgrind_state.malloc(0x1000_0000, 0x100_0000).is_ok());
+    assert!(valgrind_state.write(0x1000_0000, 0x100_0000).is_ok());
+    assert!(valgrind_state.read(0x1000_0000, 0x100_0000).is_ok());
+    assert_eq!(
+        valgrind_state.read(0x10ff_fffc, 8),
+        Err(AccessError::InvalidRead {
+            addr: 0x10ff_fffc,
+            len: 8,
+            bad_addr: 0x1100_0000,
+            state: MemState::Unallocated,
+            block: Some(Block::new(0x1000_0000, 0x100_0000)),
+            origin: None,
+        })
+    );
+    assert!(valgrind_state.free(0x1000_0000).is_ok());
+}
+
 #[test]
 fn stack_underflow() {
     let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
@@ -377,3 +1345,147 @@ fn stack_underflow() {
     );
     assert_eq!(valgrind_state.stack_pointer, 800);
 }
+
+#[test]
+fn continue_on_error() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+    valgrind_state.set_continue_on_error(true);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    valgrind_state.set_site(Some(1));
+    for _ in 0..3 {
+        // the 4 valid bytes are still written
+        assert!(valgrind_state.write(0x1000 + 28, 8).is_ok());
+    }
+    valgrind_state.set_site(Some(2));
+    assert!(valgrind_state.read(0x1000 + 28, 4).is_ok());
+    assert!(valgrind_state.free(0x2000).is_ok());
+    assert!(valgrind_state.free(0x1000).is_ok());
+    assert!(valgrind_state.read(0x1000, 4).is_ok());
+
+    let log = valgrind_state.error_log().unwrap();
+    assert_eq!(
+        log.summary(),
+        ErrorSummary {
+            errors: 5,
+            contexts: 3
+        }
+    );
+    assert_eq!(log.errors()[0].count, 3);
+    assert_eq!(log.errors()[0].error.kind(), "InvalidWrite");
+    assert_eq!(log.errors()[1].error.kind(), "InvalidFree");
+    assert_eq!(log.errors()[2].error.kind(), "UseAfterFree");
+
+    valgrind_state.set_continue_on_error(false);
+    assert!(valgrind_state.read(0x1000, 4).is_err());
+}
+
+#[test]
+fn suppressed_errors() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+    valgrind_state.set_suppressions(
+        Suppressions::parse("{\n  bad-frees\n  InvalidFree\n  addr:0x2000-0x3000\n}").unwrap(),
+    );
+
+    assert!(valgrind_state.free(0x2000).is_ok());
+    assert!(valgrind_state.free(0x2ff0).is_ok());
+    assert!(valgrind_state.free(0x3000).is_err());
+    assert!(valgrind_state.read(0x2000, 4).is_err());
+
+    valgrind_state.set_continue_on_error(true);
+    assert!(valgrind_state.free(0x2008).is_ok());
+    assert!(valgrind_state.error_log().unwrap().errors().is_empty());
+    let fired = valgrind_state.suppressions().unwrap().fired();
+    assert_eq!(fired.collect::<Vec<_>>(), [("bad-frees", 3)]);
+}
+
+#[test]
+fn allocation_and_free_stacks() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+    valgrind_state.set_stack_depth(2);
+
+    valgrind_state.enter_function(1);
+    valgrind_state.enter_function(2);
+    valgrind_state.enter_function(3);
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    valgrind_state.exit_function();
+    assert!(valgrind_state.free(0x1000).is_ok());
+
+    let err = valgrind_state.free(0x1000).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Invalid free() at 0x1000, 0 bytes inside a block of size 32 free'd"
+    );
+    let AccessError::InvalidFree {
+        freed_block: Some(block),
+        ..
+    } = err
+    else {
+        panic!("double free without the freed block");
+    };
+    assert_eq!(*block.alloc_stack, [3, 2]);
+    assert_eq!(*block.free_stack, [2, 1]);
+
+    valgrind_state.exit_function();
+    valgrind_state.exit_function();
+    assert!(valgrind_state.malloc(0x2000, 8).is_ok());
+    let Err(AccessError::InvalidRead {
+        block: Some(block), ..
+    }) = valgrind_state.read(0x2000, 4)
+    else {
+        panic!("uninitialized read not reported");
+    };
+    assert!(block.alloc_stack.is_empty());
+}
+
+#[test]
+fn copy_propagates_definedness() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.malloc(0x2000, 32).is_ok());
+    assert!(valgrind_state.write(0x1000, 8).is_ok());
+    assert!(valgrind_state.copy(0x2000, 0x1000, 16).is_ok());
+    assert!(valgrind_state.read(0x2000, 8).is_ok());
+    assert!(matches!(
+        valgrind_state.read(0x2000, 9),
+        Err(AccessError::InvalidRead {
+            bad_addr: 0x2008,
+            state: MemState::ValidToWrite,
+            ..
+        })
+    ));
+
+    // overlapping: the initialized prefix moves up by 4 bytes
+    assert!(valgrind_state.copy(0x1004, 0x1000, 16).is_ok());
+    assert!(valgrind_state.read(0x1004, 8).is_ok());
+    assert!(valgrind_state.read(0x100c, 1).is_err());
+    assert!(valgrind_state.fill(0x100c, 20).is_ok());
+    assert!(valgrind_state.read(0x1000, 32).is_ok());
+}
+
+#[test]
+fn copy_checks_both_ranges() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+
+    assert!(valgrind_state.malloc(0x1000, 32).is_ok());
+    assert!(valgrind_state.malloc(0x2000, 16).is_ok());
+    assert!(matches!(
+        valgrind_state.copy(0x2000, 0x1000, 32),
+        Err(AccessError::InvalidWrite {
+            bad_addr: 0x2010,
+            ..
+        })
+    ));
+    assert!(valgrind_state.free(0x1000).is_ok());
+    assert!(matches!(
+        valgrind_state.copy(0x2000, 0x1000, 4),
+        Err(AccessError::UseAfterFree { addr: 0x1000, .. })
+    ));
+    assert!(matches!(
+        valgrind_state.copy(0x2000, 640 * 1024 - 2, 4),
+        Err(AccessError::OutOfBounds { .. })
+    ));
+    assert!(valgrind_state.fill(0x2010, 1).is_err());
+    assert!(valgrind_state.copy(0x2000, 0x2000, 0).is_ok());
+}
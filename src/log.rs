@@ -0,0 +1,90 @@
+/*
+In continue-on-error mode `Valgrind` records errors here instead of returning them.
+Repeats of an error are folded into the first occurrence: errors with a known site
+(the code location of the offending operation) are told apart by kind and site,
+others only by their full contents.
+*/
+
+use crate::AccessError;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggedError {
+    pub error: AccessError, // first occurrence
+    pub site: Option<usize>,
+    pub stack: Vec<u32>, // function indices at the first occurrence, innermost first
+    pub count: usize,
+}
+
+/// Totals in valgrind's "N errors from M contexts" form.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ErrorSummary {
+    pub errors: usize,
+    pub contexts: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct ErrorLog {
+    errors: Vec<LoggedError>,
+    by_site: HashMap<(&'static str, usize), usize>, // index into `errors`
+}
+
+impl ErrorLog {
+    /// Distinct errors in the order they first occurred.
+    pub fn errors(&self) -> &[LoggedError] {
+        &self.errors
+    }
+    pub fn summary(&self) -> ErrorSummary {
+        ErrorSummary {
+            errors: self.errors.iter().map(|logged| logged.count).sum(),
+            contexts: self.errors.len(),
+        }
+    }
+    pub(crate) fn record(&mut self, error: AccessError, site: Option<usize>, stack: Vec<u32>) {
+        let existing = match site {
+            Some(site) => self.by_site.get(&(error.kind(), site)).copied(),
+            None => self
+                .errors
+                .iter()
+                .position(|logged| logged.site.is_none() && logged.error == error),
+        };
+        match existing {
+            Some(index) => self.errors[index].count += 1,
+            None => {
+                if let Some(site) = site {
+                    self.by_site.insert((error.kind(), site), self.errors.len());
+                }
+                self.errors.push(LoggedError {
+                    error,
+                    site,
+                    stack,
+                    count: 1,
+                });
+            }
+        }
+    }
+}
+
+#[test]
+fn folds_repeats_by_site() {
+    let mut log = ErrorLog::default();
+    let read = |addr| AccessError::OutOfBounds { addr, len: 4 };
+
+    log.record(read(0x10), Some(7), Vec::new());
+    log.record(read(0x20), Some(7), Vec::new());
+    log.record(read(0x10), Some(8), Vec::new());
+    log.record(read(0x10), None, Vec::new());
+    log.record(read(0x10), None, Vec::new());
+    log.record(read(0x20), None, Vec::new());
+    assert_eq!(
+        log.summary(),
+        ErrorSummary {
+            errors: 6,
+            contexts: 4
+        }
+    );
+    assert_eq!(log.errors()[0].error, read(0x10));
+    assert_eq!(log.errors()[0].count, 2);
+    assert_eq!(log.errors()[2].count, 2);
+    assert_eq!(log.errors()[3].count, 1);
+}
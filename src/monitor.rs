@@ -0,0 +1,292 @@
+/*
+Host-side counterpart of the hooks imported by an instrumented module, independent of
+the runtime executing it: an embedder forwards every `wasm_valgrind` import to the
+method of the same name. Loads, stores, bulk memory operations and uses of undefined
+values pass their site on to `Valgrind`; errors from the allocator and stack hooks
+have none. Accesses and nested allocator calls made from inside a wrapped allocator
+function are the allocator's own bookkeeping and are not checked.
+*/
+
+use crate::instrument::Layout;
+use crate::{AccessError, Valgrind, ValueUse};
+
+pub struct Monitor {
+    valgrind: Valgrind,
+    allocator_depth: usize,
+}
+
+impl Monitor {
+    pub fn new(valgrind: Valgrind) -> Monitor {
+        Monitor {
+            valgrind,
+            allocator_depth: 0,
+        }
+    }
+    /// Creates a monitor for a memory of `mem_size` bytes laid out as `layout`, with the
+    /// stack below `layout.stack_top` and the static data already valid.
+    pub fn with_layout(mem_size: usize, layout: &Layout) -> Result<Monitor, AccessError> {
+        let mut valgrind = Valgrind::new(mem_size, layout.stack_top.unwrap_or(0) as usize);
+        if let Some(data) = &layout.static_data {
+            valgrind.static_data(data.start as usize, data.len())?;
+        }
+        Ok(Monitor::new(valgrind))
+    }
+    pub fn valgrind(&self) -> &Valgrind {
+        &self.valgrind
+    }
+    pub fn valgrind_mut(&mut self) -> &mut Valgrind {
+        &mut self.valgrind
+    }
+    /// Whether execution is currently inside a wrapped allocator function.
+    pub fn in_allocator(&self) -> bool {
+        self.allocator_depth > 0
+    }
+    pub fn load(&mut self, addr: u32, offset: u32, len: u32, site: u32) -> Result<(), AccessError> {
+        if self.in_allocator() {
+            return Ok(());
+        }
+        self.valgrind.set_site(Some(site as usize));
+        self.valgrind
+            .read(addr as usize + offset as usize, len as usize)
+    }
+    pub fn store(
+        &mut self,
+        addr: u32,
+        offset: u32,
+        len: u32,
+        site: u32,
+    ) -> Result<(), AccessError> {
+        if self.in_allocator() {
+            return Ok(());
+        }
+        self.valgrind.set_site(Some(site as usize));
+        self.valgrind
+            .write(addr as usize + offset as usize, len as usize)
+    }
+    /// Returns 1 if the loaded value is undefined, else 0.
+    pub fn load_value(
+        &mut self,
+        addr: u32,
+        offset: u32,
+        len: u32,
+        site: u32,
+    ) -> Result<u32, AccessError> {
+        if self.in_allocator() {
+            return Ok(0);
+        }
+        self.valgrind.set_site(Some(site as usize));
+        let undefined = self
+            .valgrind
+            .load_value(addr as usize + offset as usize, len as usize)?;
+        Ok(undefined as u32)
+    }
+    pub fn store_value(
+        &mut self,
+        addr: u32,
+        offset: u32,
+        len: u32,
+        undefined: u32,
+        site: u32,
+    ) -> Result<(), AccessError> {
+        if self.in_allocator() {
+            return Ok(());
+        }
+        self.valgrind.set_site(Some(site as usize));
+        self.valgrind.store_value(
+            addr as usize + offset as usize,
+            len as usize,
+            undefined != 0,
+        )
+    }
+    /// `point` is a `ValueUse::code`; unknown points are ignored.
+    pub fn undefined_value(&mut self, point: u32, site: u32) -> Result<(), AccessError> {
+        let Some(point) = ValueUse::from_code(point) else {
+            return Ok(());
+        };
+        if self.in_allocator() {
+            return Ok(());
+        }
+        self.valgrind.set_site(Some(site as usize));
+        self.valgrind.undefined_value(point)
+    }
+    pub fn copy(&mut self, dst: u32, src: u32, len: u32, site: u32) -> Result<(), AccessError> {
+        if self.in_allocator() {
+            return Ok(());
+        }
+        self.valgrind.set_site(Some(site as usize));
+        self.valgrind.copy(dst as usize, src as usize, len as usize)
+    }
+    pub fn move_value(
+        &mut self,
+        dst: u32,
+        dst_offset: u32,
+        src: u32,
+        src_offset: u32,
+        len: u32,
+        site: u32,
+    ) -> Result<(), AccessError> {
+        if self.in_allocator() {
+            return Ok(());
+        }
+        self.valgrind.set_site(Some(site as usize));
+        self.valgrind.move_value(
+            dst as usize + dst_offset as usize,
+            src as usize + src_offset as usize,
+            len as usize,
+        )
+    }
+    pub fn fill(&mut self, dst: u32, len: u32, site: u32) -> Result<(), AccessError> {
+        if self.in_allocator() {
+            return Ok(());
+        }
+        self.valgrind.set_site(Some(site as usize));
+        self.valgrind.fill(dst as usize, len as usize)
+    }
+    pub fn alloc_enter(&mut self) {
+        self.allocator_depth += 1;
+    }
+    pub fn alloc_exit(&mut self) {
+        self.allocator_depth = self.allocator_depth.saturating_sub(1);
+    }
+    /// A null result means the allocation failed and is ignored.
+    pub fn malloc(&mut self, ret: u32, size: u32) -> Result<(), AccessError> {
+        if self.in_allocator() || ret == 0 {
+            return Ok(());
+        }
+        self.valgrind.set_site(None);
+        self.valgrind.malloc(ret as usize, size as usize)
+    }
+    /// Called before the block is released; `free(NULL)` is a no-op.
+    pub fn free(&mut self, ptr: u32) -> Result<(), AccessError> {
+        if self.in_allocator() || ptr == 0 {
+            return Ok(());
+        }
+        self.valgrind.set_site(None);
+        self.valgrind.free(ptr as usize)
+    }
+    pub fn calloc(&mut self, ret: u32, nmemb: u32, size: u32) -> Result<(), AccessError> {
+        if self.in_allocator() || ret == 0 {
+            return Ok(());
+        }
+        self.valgrind.set_site(None);
+        self.valgrind
+            .calloc(ret as usize, nmemb as usize, size as usize)
+    }
+    /// `realloc(NULL, size)` behaves like `malloc`, and `realloc(ptr, 0)` returning null
+    /// like `free`. Any other null result leaves the old block alive.
+    pub fn realloc(&mut self, ret: u32, ptr: u32, size: u32) -> Result<(), AccessError> {
+        if self.in_allocator() {
+            return Ok(());
+        }
+        self.valgrind.set_site(None);
+        match (ptr, ret) {
+            (0, _) => self.malloc(ret, size),
+            (_, 0) if size == 0 => self.valgrind.free(ptr as usize),
+            (_, 0) => Ok(()),
+            _ => self
+                .valgrind
+                .realloc(ptr as usize, ret as usize, size as usize),
+        }
+    }
+    pub fn aligned_alloc(&mut self, ret: u32, _align: u32, size: u32) -> Result<(), AccessError> {
+        self.malloc(ret, size)
+    }
+    /// Function entries and exits are followed everywhere, to keep the call stack balanced.
+    pub fn enter(&mut self, func: u32) {
+        self.valgrind.enter_function(func);
+    }
+    pub fn exit(&mut self) {
+        self.valgrind.exit_function();
+    }
+    /// Follows the stack pointer even inside the allocator, whose frames are real.
+    pub fn stack_pointer(&mut self, new_sp: u32) -> Result<(), AccessError> {
+        self.valgrind.set_site(None);
+        self.valgrind.update_stack_pointer(new_sp as usize)
+    }
+}
+
+#[test]
+fn allocator_internals_are_not_checked() {
+    let mut monitor = Monitor::new(Valgrind::new(640 * 1024, 1024));
+
+    // realloc(NULL, 32) implemented as a nested malloc that touches its header
+    monitor.alloc_enter();
+    monitor.alloc_enter();
+    assert_eq!(monitor.store(0x1000 - 8, 0, 8, 0), Ok(()));
+    monitor.alloc_exit();
+    assert_eq!(monitor.malloc(0x1000, 32), Ok(()));
+    monitor.alloc_exit();
+    assert!(!monitor.in_allocator());
+    assert_eq!(monitor.realloc(0x1000, 0, 32), Ok(()));
+
+    assert_eq!(monitor.store(0x1000, 28, 4, 0), Ok(()));
+    assert_eq!(monitor.load(0x1000, 28, 4, 0), Ok(()));
+    assert!(monitor.load(0x1000, 32, 4, 0).is_err());
+    assert!(monitor.load(0x1000 - 8, 0, 8, 0).is_err());
+}
+
+#[test]
+fn null_pointers() {
+    let mut monitor = Monitor::new(Valgrind::new(640 * 1024, 1024));
+
+    assert_eq!(monitor.malloc(0, 32), Ok(()));
+    assert_eq!(monitor.free(0), Ok(()));
+    assert_eq!(monitor.calloc(0x1000, 4, 8), Ok(()));
+    assert_eq!(monitor.realloc(0, 0x1000, 64), Ok(()));
+    assert_eq!(monitor.load(0x1000, 0, 32, 0), Ok(()));
+    assert_eq!(monitor.realloc(0, 0x1000, 0), Ok(()));
+    assert!(matches!(
+        monitor.free(0x1000),
+        Err(AccessError::InvalidFree { .. })
+    ));
+}
+
+#[test]
+fn stack_pointer_moves() {
+    let mut monitor = Monitor::new(Valgrind::new(640 * 1024, 1024));
+
+    assert!(monitor.load(1024 - 16, 0, 4, 0).is_err());
+    assert_eq!(monitor.stack_pointer(1024 - 16), Ok(()));
+    assert_eq!(monitor.store(1024 - 16, 0, 4, 0), Ok(()));
+    assert_eq!(monitor.stack_pointer(1024), Ok(()));
+    assert!(monitor.load(1024 - 16, 0, 4, 0).is_err());
+}
+
+#[test]
+fn stacks_of_allocator_calls() {
+    let mut monitor = Monitor::new(Valgrind::new(640 * 1024, 1024));
+
+    // main (1) calls the wrapped malloc (5), which calls sbrk (6)
+    monitor.enter(1);
+    monitor.alloc_enter();
+    monitor.enter(5);
+    monitor.enter(6);
+    monitor.exit();
+    monitor.exit();
+    monitor.alloc_exit();
+    assert_eq!(monitor.malloc(0x1000, 32), Ok(()));
+    let Err(AccessError::InvalidRead {
+        block: Some(block), ..
+    }) = monitor.load(0x1000, 0, 4, 0)
+    else {
+        panic!("uninitialized read not reported");
+    };
+    assert_eq!(*block.alloc_stack, [1]);
+}
+
+#[test]
+fn static_data_from_layout() {
+    let layout = Layout {
+        stack_top: Some(1024),
+        static_data: Some(1024..1100),
+    };
+    let mut monitor = Monitor::with_layout(640 * 1024, &layout).unwrap();
+
+    assert_eq!(monitor.load(1024, 72, 4, 0), Ok(()));
+    assert!(monitor.load(1100, 0, 4, 0).is_err());
+    assert_eq!(monitor.stack_pointer(1000), Ok(()));
+    assert!(matches!(
+        monitor.malloc(1096, 8),
+        Err(AccessError::DoubleMalloc { .. })
+    ));
+}
@@ -0,0 +1,222 @@
+/*
+Origin tracking, like memcheck's `--track-origins=yes`: every 4-byte granule of memory
+can hold the id of the allocation that left it undefined, so that a report of undefined
+bytes can say where they came from. Ids index a table of distinct origins, i.e. kinds
+and allocation stacks, so repeated allocations from the same place share one. Granules
+are stored in pages that are only materialized once they hold an origin.
+
+Origins are recorded at heap and stack allocation and follow memory copies. A value
+that reaches memory through wasm locals leaves its bytes without an origin.
+*/
+
+use crate::Valgrind;
+use std::collections::HashMap;
+use std::fmt;
+
+const GRANULE: usize = 4;
+const PAGE_GRANULES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OriginKind {
+    Heap,
+    Stack,
+}
+
+/// Where undefined bytes were allocated. The stack holds wasm function indices,
+/// innermost first, and is empty unless the module reports its calls.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Origin {
+    pub kind: OriginKind,
+    pub stack: Box<[u32]>,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let how = match self.kind {
+            OriginKind::Heap => "a heap allocation",
+            OriginKind::Stack => "a stack allocation",
+        };
+        write!(f, "Uninitialised value was created by {}", how)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Origins {
+    pages: Vec<Option<Box<[u32]>>>, // origin id of each granule, 0 for none
+    table: Vec<Origin>,             // origin of id `i + 1`
+    ids: HashMap<Origin, u32>,
+}
+
+impl Origins {
+    fn id(&self, granule: usize) -> u32 {
+        match self.pages.get(granule / PAGE_GRANULES) {
+            Some(Some(page)) => page[granule % PAGE_GRANULES],
+            _ => 0,
+        }
+    }
+    fn set_id(&mut self, granule: usize, id: u32) {
+        let page = granule / PAGE_GRANULES;
+        if page >= self.pages.len() {
+            if id == 0 {
+                return;
+            }
+            self.pages.resize(page + 1, None);
+        }
+        match &mut self.pages[page] {
+            Some(ids) => ids[granule % PAGE_GRANULES] = id,
+            None if id == 0 => {}
+            slot => {
+                let mut ids = vec![0; PAGE_GRANULES].into_boxed_slice();
+                ids[granule % PAGE_GRANULES] = id;
+                *slot = Some(ids);
+            }
+        }
+    }
+    fn granules(addr: usize, len: usize) -> std::ops::Range<usize> {
+        addr / GRANULE..(addr + len).div_ceil(GRANULE)
+    }
+    /// Gives the granules of `addr..addr + len` the origin `origin`, or none.
+    pub(crate) fn set(&mut self, addr: usize, len: usize, origin: Option<Origin>) {
+        let id = match origin {
+            Some(origin) => match self.ids.get(&origin) {
+                Some(id) => *id,
+                None => {
+                    self.table.push(origin.clone());
+                    let id = self.table.len() as u32;
+                    self.ids.insert(origin, id);
+                    id
+                }
+            },
+            None => 0,
+        };
+        for granule in Origins::granules(addr, len) {
+            self.set_id(granule, id);
+        }
+    }
+    pub(crate) fn get(&self, addr: usize) -> Option<&Origin> {
+        let id = self.id(addr / GRANULE);
+        id.checked_sub(1).map(|index| &self.table[index as usize])
+    }
+    /// Gives each granule of `dst..dst + len` the origin of the matching byte of
+    /// `src..src + len`. The ranges may overlap.
+    pub(crate) fn copy_within(&mut self, src: usize, dst: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let ids = Origins::granules(dst, len)
+            .map(|granule| {
+                let byte = (granule * GRANULE).max(dst) - dst;
+                self.id((src + byte) / GRANULE)
+            })
+            .collect::<Vec<_>>();
+        for (granule, id) in Origins::granules(dst, len).zip(ids) {
+            self.set_id(granule, id);
+        }
+    }
+}
+
+impl Valgrind {
+    /// Records where undefined bytes were allocated, so that reports of uninitialised
+    /// reads carry an `Origin`. Turning tracking off forgets all origins.
+    pub fn set_track_origins(&mut self, enabled: bool) {
+        match (enabled, &self.origins) {
+            (true, None) => self.origins = Some(Origins::default()),
+            (false, Some(_)) => self.origins = None,
+            _ => {}
+        }
+    }
+    pub fn track_origins(&self) -> bool {
+        self.origins.is_some()
+    }
+    /// Where the undefined byte at `addr` was allocated, if origins are tracked.
+    pub fn origin_at(&self, addr: usize) -> Option<Origin> {
+        self.origins.as_ref()?.get(addr).cloned()
+    }
+    /// Gives `addr..addr + len` an origin of `kind` allocated by the current call stack,
+    /// or clears it.
+    pub(crate) fn record_origin(&mut self, addr: usize, len: usize, kind: Option<OriginKind>) {
+        if self.origins.is_none() {
+            return;
+        }
+        let origin = kind.map(|kind| Origin {
+            kind,
+            stack: self.call_stack().into(),
+        });
+        if let Some(origins) = &mut self.origins {
+            origins.set(addr, len, origin);
+        }
+    }
+    pub(crate) fn copy_origins(&mut self, src: usize, dst: usize, len: usize) {
+        if let Some(origins) = &mut self.origins {
+            origins.copy_within(src, dst, len);
+        }
+    }
+}
+
+#[test]
+fn granule_origins() {
+    let heap = Origin {
+        kind: OriginKind::Heap,
+        stack: Box::new([3, 1]),
+    };
+    let mut origins = Origins::default();
+    assert_eq!(origins.get(0x10000), None);
+
+    origins.set(0x1000, 6, Some(heap.clone()));
+    origins.set(0x2000, 8, Some(heap.clone()));
+    assert_eq!(origins.table.len(), 1);
+    assert_eq!(origins.get(0x1007), Some(&heap));
+    assert_eq!(origins.get(0x1008), None);
+
+    // the granules of the destination take the origins of the source bytes they start at
+    origins.copy_within(0x1002, 0x3006, 8);
+    assert_eq!(origins.get(0x3004), Some(&heap));
+    assert_eq!(origins.get(0x300c), None);
+    origins.set(0x2004, 1, None);
+    assert_eq!(origins.get(0x2006), None);
+    assert_eq!(origins.get(0x2000), Some(&heap));
+    // only pages holding origins are materialized
+    assert!(origins.pages[0].is_none());
+}
+
+#[test]
+fn origins_of_uninitialised_reads() {
+    use crate::{AccessError, UndefinedChecks, UsePoints};
+
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+    valgrind_state.set_track_origins(true);
+    valgrind_state.enter_function(7);
+    assert!(valgrind_state.malloc(0x1000, 16).is_ok());
+    valgrind_state.exit_function();
+    let heap = Origin {
+        kind: OriginKind::Heap,
+        stack: Box::new([7]),
+    };
+    let err = valgrind_state.read(0x1000, 4).unwrap_err();
+    assert_eq!(err.origin(), Some(&heap));
+
+    // copied bytes keep their origin
+    valgrind_state.set_undefined_checks(UndefinedChecks::OnUse(UsePoints::default()));
+    assert!(valgrind_state.malloc(0x2000, 16).is_ok());
+    assert!(valgrind_state.write(0x2000, 16).is_ok());
+    assert!(valgrind_state.copy(0x2008, 0x1000, 8).is_ok());
+    assert!(matches!(
+        valgrind_state.host_read(0x2000, 16),
+        Err(AccessError::UninitializedUse {
+            bad_addr: 0x2008,
+            origin: Some(origin),
+            ..
+        }) if origin == heap
+    ));
+
+    // stack allocations below the old stack pointer
+    assert!(valgrind_state.update_stack_pointer(0x3f0).is_ok());
+    assert_eq!(
+        valgrind_state.origin_at(0x3fc).map(|origin| origin.kind),
+        Some(OriginKind::Stack)
+    );
+
+    valgrind_state.set_undefined_checks(UndefinedChecks::OnLoad);
+    valgrind_state.set_track_origins(false);
+    assert_eq!(valgrind_state.read(0x1000, 4).unwrap_err().origin(), None);
+}
@@ -0,0 +1,498 @@
+/*
+Machine-readable reports of the errors collected in an `ErrorLog` and of a leak check,
+for dashboards and code-scanning tools. `to_json` produces this crate's own schema,
+whose `version` is bumped on incompatible changes; `to_sarif` produces a SARIF 2.1.0
+log with one rule per error kind.
+
+Stack frames are named from the module's name section and located from its DWARF when
+those are given. The frame of the offending instruction is expanded into the functions
+inlined there.
+*/
+
+use crate::dwarf::{DebugInfo, SourceFrame};
+use crate::instrument::Symbols;
+use crate::{AccessError, Block, LeakKind, LeakReport, LeakedBlock, LoggedError};
+use serde_json::{json, Value};
+
+pub const JSON_VERSION: u32 = 1;
+
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+
+pub struct Report<'a> {
+    errors: &'a [LoggedError],
+    leaks: Option<&'a LeakReport>,
+    symbols: Option<&'a Symbols>,
+    debug_info: Option<&'a DebugInfo>,
+    module: Option<&'a str>,
+}
+
+/// A resolved stack frame; inlined frames have no function index of their own.
+struct Frame {
+    func: Option<u32>,
+    name: Option<String>,
+    source: Option<SourceFrame>,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(errors: &'a [LoggedError]) -> Report<'a> {
+        Report {
+            errors,
+            leaks: None,
+            symbols: None,
+            debug_info: None,
+            module: None,
+        }
+    }
+    pub fn set_leaks(&mut self, leaks: &'a LeakReport) -> &mut Report<'a> {
+        self.leaks = Some(leaks);
+        self
+    }
+    /// Names frames after the functions in `symbols`.
+    pub fn set_symbols(&mut self, symbols: &'a Symbols) -> &mut Report<'a> {
+        self.symbols = Some(symbols);
+        self
+    }
+    /// Gives frames source locations.
+    pub fn set_debug_info(&mut self, debug_info: &'a DebugInfo) -> &mut Report<'a> {
+        self.debug_info = Some(debug_info);
+        self
+    }
+    /// The URI of the module, for locations without source information.
+    pub fn set_module(&mut self, uri: &'a str) -> &mut Report<'a> {
+        self.module = Some(uri);
+        self
+    }
+
+    pub fn to_json(&self) -> Value {
+        let errors = self.errors.iter().map(|logged| {
+            let err = &logged.error;
+            let (bad_addr, state) = match err {
+                AccessError::InvalidRead {
+                    bad_addr, state, ..
+                }
+                | AccessError::InvalidWrite {
+                    bad_addr, state, ..
+                } => (Some(*bad_addr), Some(format!("{:?}", state))),
+                AccessError::HeapBufferOverflow { bad_addr, .. }
+                | AccessError::UninitializedUse { bad_addr, .. } => (Some(*bad_addr), None),
+                _ => (None, None),
+            };
+            json!({
+                "kind": err.kind(),
+                "message": err.to_string(),
+                "count": logged.count,
+                "site": logged.site,
+                "address": err.addr(),
+                "size": err.size(),
+                "bad_address": bad_addr,
+                "state": state,
+                "stack": self.json_frames(&self.error_frames(logged)),
+                "block": err.block().map(|block| self.json_block(block)),
+                "frame": err.frame().map(|frame| json!({
+                    "address": frame.addr,
+                    "size": frame.len,
+                    "function": frame.func,
+                })),
+                "origin": err.origin().map(|origin| json!({
+                    "kind": format!("{:?}", origin.kind),
+                    "stack": self.json_frames(&self.frames(&origin.stack)),
+                })),
+            })
+        });
+        let leaks = self.leaks.iter().flat_map(|leaks| &leaks.blocks);
+        let leaks = leaks.map(|leak| {
+            json!({
+                "kind": format!("{:?}", leak.kind),
+                "address": leak.addr,
+                "size": leak.len,
+                "alloc_stack": self.json_frames(&self.frames(&leak.alloc_stack)),
+            })
+        });
+        let summary = |total: Option<crate::LeakTotal>| {
+            total.map(|total| json!({ "blocks": total.blocks, "bytes": total.bytes }))
+        };
+        json!({
+            "version": JSON_VERSION,
+            "module": self.module,
+            "errors": errors.collect::<Vec<_>>(),
+            "leaks": leaks.collect::<Vec<_>>(),
+            "summary": {
+                "errors": self.errors.iter().map(|logged| logged.count).sum::<usize>(),
+                "contexts": self.errors.len(),
+                "definitely_lost": summary(self.leaks.map(LeakReport::definitely_lost)),
+                "possibly_lost": summary(self.leaks.map(LeakReport::possibly_lost)),
+                "still_reachable": summary(self.leaks.map(LeakReport::still_reachable)),
+            },
+        })
+    }
+
+    pub fn to_sarif(&self) -> Value {
+        let mut rules: Vec<&str> = Vec::new();
+        let mut rule_index = |id: &'static str| match rules.iter().position(|rule| *rule == id) {
+            Some(index) => index,
+            None => {
+                rules.push(id);
+                rules.len() - 1
+            }
+        };
+        let mut results = Vec::new();
+        for logged in self.errors {
+            let err = &logged.error;
+            let frames = self.error_frames(logged);
+            let mut stacks = vec![self.sarif_stack("Error", &frames)];
+            if let Some(block) = err.block() {
+                if !block.free_stack.is_empty() {
+                    let frames = self.frames(&block.free_stack);
+                    stacks.push(self.sarif_stack("Block was free'd at", &frames));
+                }
+                if !block.alloc_stack.is_empty() {
+                    let frames = self.frames(&block.alloc_stack);
+                    stacks.push(self.sarif_stack("Block was alloc'd at", &frames));
+                }
+            }
+            if let Some(func) = err.frame().and_then(|frame| frame.func) {
+                let frames = self.frames(&[func]);
+                stacks.push(self.sarif_stack("Stack frame was claimed by", &frames));
+            }
+            if let Some(origin) = err.origin() {
+                let frames = self.frames(&origin.stack);
+                stacks.push(self.sarif_stack(&origin.to_string(), &frames));
+            }
+            let location = match (frames.first(), logged.site) {
+                (Some(frame), _) if frame.source.is_some() => Some(self.sarif_location(frame)),
+                (_, Some(site)) if self.module.is_some() => Some(json!({
+                    "physicalLocation": {
+                        "artifactLocation": { "uri": self.module },
+                        "region": { "byteOffset": site },
+                    },
+                })),
+                (frame, _) => frame.map(|frame| self.sarif_location(frame)),
+            };
+            results.push(json!({
+                "ruleId": err.kind(),
+                "ruleIndex": rule_index(err.kind()),
+                "level": "error",
+                "message": { "text": err.to_string() },
+                "locations": location.into_iter().collect::<Vec<_>>(),
+                "stacks": stacks,
+                "occurrenceCount": logged.count,
+            }));
+        }
+        for leak in self.leaks.iter().flat_map(|leaks| &leaks.blocks) {
+            let (id, level, how) = match leak.kind {
+                LeakKind::DefinitelyLost => ("Leak_DefinitelyLost", "error", "definitely lost"),
+                LeakKind::PossiblyLost => ("Leak_PossiblyLost", "error", "possibly lost"),
+                LeakKind::StillReachable => ("Leak_StillReachable", "note", "still reachable"),
+            };
+            let frames = self.frames(&leak.alloc_stack);
+            let location = frames.first().map(|frame| self.sarif_location(frame));
+            let stacks =
+                (!frames.is_empty()).then(|| self.sarif_stack("Block was alloc'd at", &frames));
+            results.push(json!({
+                "ruleId": id,
+                "ruleIndex": rule_index(id),
+                "level": level,
+                "message": { "text": leak_message(leak, how) },
+                "locations": location.into_iter().collect::<Vec<_>>(),
+                "stacks": stacks.into_iter().collect::<Vec<_>>(),
+            }));
+        }
+        let rules = rules.iter().map(|id| json!({ "id": id, "name": id }));
+        json!({
+            "$schema": SARIF_SCHEMA,
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {
+                    "driver": {
+                        "name": "wasm-valgrind",
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "rules": rules.collect::<Vec<_>>(),
+                    },
+                },
+                "results": results,
+            }],
+        })
+    }
+
+    /// The stack of a logged error, starting with the functions inlined at its site.
+    fn error_frames(&self, logged: &LoggedError) -> Vec<Frame> {
+        let inlined = match (self.debug_info, logged.site) {
+            (Some(debug_info), Some(site)) => debug_info.locate(site),
+            _ => Vec::new(),
+        };
+        if inlined.is_empty() {
+            return self.frames(&logged.stack);
+        }
+        let count = inlined.len();
+        let mut frames: Vec<Frame> = inlined
+            .into_iter()
+            .enumerate()
+            .map(|(depth, source)| Frame {
+                // the outermost of the inlined frames is the function itself
+                func: logged.stack.first().copied().filter(|_| depth + 1 == count),
+                name: source.function.clone(),
+                source: Some(source),
+            })
+            .collect();
+        frames.extend(self.frames(logged.stack.get(1..).unwrap_or(&[])));
+        frames
+    }
+    fn frames(&self, stack: &[u32]) -> Vec<Frame> {
+        stack
+            .iter()
+            .map(|func| {
+                let source = self.debug_info.and_then(|info| info.locate_function(*func));
+                let name = source.as_ref().and_then(|source| source.function.clone());
+                let name = name.or_else(|| self.symbols?.name(*func).map(str::to_string));
+                Frame {
+                    func: Some(*func),
+                    name,
+                    source,
+                }
+            })
+            .collect()
+    }
+    fn json_frames(&self, frames: &[Frame]) -> Vec<Value> {
+        frames
+            .iter()
+            .map(|frame| {
+                let source = frame.source.as_ref();
+                json!({
+                    "function": frame.func,
+                    "name": frame.name,
+                    "file": source.and_then(|source| source.file.as_deref()),
+                    "line": source.and_then(|source| source.line),
+                    "column": source.and_then(|source| source.column),
+                })
+            })
+            .collect()
+    }
+    fn json_block(&self, block: &Block) -> Value {
+        json!({
+            "address": block.addr,
+            "size": block.len,
+            "alloc_stack": self.json_frames(&self.frames(&block.alloc_stack)),
+            "free_stack": self.json_frames(&self.frames(&block.free_stack)),
+        })
+    }
+    fn sarif_stack(&self, message: &str, frames: &[Frame]) -> Value {
+        let frames = frames
+            .iter()
+            .map(|frame| json!({ "location": self.sarif_location(frame) }));
+        json!({
+            "message": { "text": message },
+            "frames": frames.collect::<Vec<_>>(),
+        })
+    }
+    fn sarif_location(&self, frame: &Frame) -> Value {
+        let name = match (&frame.name, frame.func) {
+            (Some(name), _) => name.clone(),
+            (None, Some(func)) => format!("function {}", func),
+            (None, None) => "???".to_string(),
+        };
+        let mut location = json!({
+            "logicalLocations": [{ "name": name, "kind": "function" }],
+        });
+        if let Some(SourceFrame {
+            file: Some(file),
+            line,
+            column,
+            ..
+        }) = &frame.source
+        {
+            // SARIF lines and columns start at 1; 0 means unknown in DWARF
+            let mut region = serde_json::Map::new();
+            if let Some(line) = line.filter(|line| *line > 0) {
+                region.insert("startLine".into(), line.into());
+                if let Some(column) = column.filter(|column| *column > 0) {
+                    region.insert("startColumn".into(), column.into());
+                }
+            }
+            location["physicalLocation"] = json!({
+                "artifactLocation": { "uri": file },
+                "region": region,
+            });
+        }
+        location
+    }
+}
+
+fn leak_message(leak: &LeakedBlock, how: &str) -> String {
+    format!(
+        "{} bytes in a block at {:#x} are {}",
+        leak.len, leak.addr, how
+    )
+}
+
+#[cfg(test)]
+fn sample_errors() -> Vec<LoggedError> {
+    let mut freed_block = Block::new(0x1000, 16);
+    freed_block.alloc_stack = Box::new([1, 0]);
+    freed_block.free_stack = Box::new([2, 0]);
+    vec![
+        LoggedError {
+            error: AccessError::UseAfterFree {
+                addr: 0x1004,
+                len: 4,
+                freed_block,
+            },
+            site: Some(0x40),
+            stack: vec![3, 0],
+            count: 2,
+        },
+        LoggedError {
+            error: AccessError::OutOfBounds {
+                addr: 0x20000,
+                len: 8,
+            },
+            site: None,
+            stack: Vec::new(),
+            count: 1,
+        },
+    ]
+}
+
+#[test]
+fn json_report() {
+    let errors = sample_errors();
+    let symbols = crate::instrument::symbols(
+        &wat::parse_str("(module (func $main) (func $malloc) (func $free) (func $use_after_free))")
+            .unwrap(),
+    )
+    .unwrap();
+    let leaks = LeakReport {
+        blocks: vec![LeakedBlock {
+            addr: 0x2000,
+            len: 24,
+            kind: LeakKind::DefinitelyLost,
+            alloc_stack: Box::new([1]),
+        }],
+    };
+    let json = Report::new(&errors)
+        .set_leaks(&leaks)
+        .set_symbols(&symbols)
+        .to_json();
+
+    assert_eq!(json["version"], JSON_VERSION);
+    let uaf = &json["errors"][0];
+    assert_eq!(uaf["kind"], "UseAfterFree");
+    assert_eq!((&uaf["address"], &uaf["size"]), (&json!(0x1004), &json!(4)));
+    assert_eq!((&uaf["count"], &uaf["site"]), (&json!(2), &json!(0x40)));
+    assert_eq!(
+        uaf["stack"][0],
+        json!({ "function": 3, "name": "use_after_free", "file": null, "line": null, "column": null })
+    );
+    assert_eq!(uaf["block"]["free_stack"][0]["name"], "free");
+    assert_eq!(uaf["block"]["alloc_stack"][1]["name"], "main");
+    let oob = &json["errors"][1];
+    assert_eq!((&oob["site"], &oob["block"]), (&Value::Null, &Value::Null));
+    assert_eq!(json["leaks"][0]["kind"], "DefinitelyLost");
+    assert_eq!(json["leaks"][0]["alloc_stack"][0]["name"], "malloc");
+    assert_eq!(uaf["origin"], Value::Null);
+    assert_eq!(
+        json["summary"],
+        json!({
+            "errors": 3,
+            "contexts": 2,
+            "definitely_lost": { "blocks": 1, "bytes": 24 },
+            "possibly_lost": { "blocks": 0, "bytes": 0 },
+            "still_reachable": { "blocks": 0, "bytes": 0 },
+        })
+    );
+}
+
+#[test]
+fn sarif_report() {
+    let errors = sample_errors();
+    let sarif = Report::new(&errors).set_module("app.wasm").to_sarif();
+
+    assert_eq!(sarif["version"], "2.1.0");
+    let run = &sarif["runs"][0];
+    assert_eq!(run["tool"]["driver"]["name"], "wasm-valgrind");
+    assert_eq!(run["tool"]["driver"]["rules"][1]["id"], "OutOfBounds");
+    let uaf = &run["results"][0];
+    assert_eq!(
+        (&uaf["ruleId"], &uaf["ruleIndex"]),
+        (&json!("UseAfterFree"), &json!(0))
+    );
+    assert_eq!(uaf["occurrenceCount"], 2);
+    // no debug info: the site is located in the module itself
+    assert_eq!(
+        uaf["locations"][0]["physicalLocation"],
+        json!({ "artifactLocation": { "uri": "app.wasm" }, "region": { "byteOffset": 0x40 } })
+    );
+    let stacks = uaf["stacks"].as_array().unwrap();
+    assert_eq!(stacks.len(), 3);
+    assert_eq!(stacks[1]["message"]["text"], "Block was free'd at");
+    assert_eq!(
+        stacks[0]["frames"][0]["location"]["logicalLocations"][0]["name"],
+        "function 3"
+    );
+    assert_eq!(run["results"][1]["locations"], json!([]));
+}
+
+#[test]
+fn sarif_source_locations() {
+    let (wasm, sites) = crate::dwarf::module_with_dwarf();
+    let debug_info = DebugInfo::parse(&wasm).unwrap();
+    let errors = vec![LoggedError {
+        error: AccessError::OutOfBounds {
+            addr: 0x20000,
+            len: 4,
+        },
+        site: Some(sites[1]),
+        stack: vec![0],
+        count: 1,
+    }];
+    let sarif = Report::new(&errors).set_debug_info(&debug_info).to_sarif();
+
+    let result = &sarif["runs"][0]["results"][0];
+    assert_eq!(
+        result["locations"][0],
+        json!({
+            "logicalLocations": [{ "name": "helper", "kind": "function" }],
+            "physicalLocation": {
+                "artifactLocation": { "uri": "main.c" },
+                "region": { "startLine": 10, "startColumn": 3 },
+            },
+        })
+    );
+    let frames = result["stacks"][0]["frames"].as_array().unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(
+        frames[1]["location"]["physicalLocation"]["region"],
+        json!({ "startLine": 5 })
+    );
+}
+
+#[test]
+fn origin_report() {
+    let errors = vec![LoggedError {
+        error: AccessError::InvalidRead {
+            addr: 0x1000,
+            len: 4,
+            bad_addr: 0x1000,
+            state: crate::MemState::ValidToWrite,
+            block: Some(Block::new(0x1000, 16)),
+            origin: Some(crate::Origin {
+                kind: crate::OriginKind::Heap,
+                stack: Box::new([1, 0]),
+            }),
+        },
+        site: None,
+        stack: vec![2, 0],
+        count: 1,
+    }];
+    let report = Report::new(&errors);
+
+    let json = report.to_json();
+    let origin = &json["errors"][0]["origin"];
+    assert_eq!(origin["kind"], "Heap");
+    assert_eq!(origin["stack"][1]["function"], 0);
+    let sarif = report.to_sarif();
+    let stacks = &sarif["runs"][0]["results"][0]["stacks"];
+    assert_eq!(
+        stacks[1]["message"]["text"],
+        "Uninitialised value was created by a heap allocation"
+    );
+}
@@ -0,0 +1,298 @@
+/*
+Shadow memory is split into fixed-size pages. A page whose bytes all share one
+state is stored as a single `MemState`; a detailed per-byte copy is only
+materialized once an operation splits the page, and is collapsed back as soon as
+the page becomes uniform again. Detailed pages count their bytes in each state, so
+telling whether a write made the page uniform costs no more than the write itself.
+*/
+
+use crate::MemState;
+use std::cmp::min;
+
+pub(crate) const SHADOW_PAGE_SIZE: usize = 4096;
+const NUM_STATES: usize = MemState::Redzone as usize + 1;
+
+#[derive(Debug, Clone)]
+enum Page {
+    Uniform(MemState),
+    Detailed {
+        bytes: Box<[MemState]>,
+        counts: [u16; NUM_STATES], // bytes in each state
+    },
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ShadowMemory {
+    pages: Vec<Page>,
+    len: usize,
+}
+
+impl ShadowMemory {
+    pub(crate) fn new(len: usize, state: MemState) -> ShadowMemory {
+        let num_pages = len.div_ceil(SHADOW_PAGE_SIZE);
+        ShadowMemory {
+            pages: vec![Page::Uniform(state); num_pages],
+            len,
+        }
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+    pub(crate) fn get(&self, addr: usize) -> MemState {
+        assert!(addr < self.len, "shadow access out of bounds");
+        match &self.pages[addr / SHADOW_PAGE_SIZE] {
+            Page::Uniform(state) => *state,
+            Page::Detailed { bytes, .. } => bytes[addr % SHADOW_PAGE_SIZE],
+        }
+    }
+    /// Sets every byte in `addr..addr + len` to `state`.
+    pub(crate) fn set_range(&mut self, addr: usize, len: usize, state: MemState) {
+        assert!(addr + len <= self.len, "shadow access out of bounds");
+        let end = addr + len;
+        let mut cur = addr;
+        while cur < end {
+            let page = cur / SHADOW_PAGE_SIZE;
+            let page_start = page * SHADOW_PAGE_SIZE;
+            let page_len = self.page_len(page);
+            let from = cur - page_start;
+            let to = min(end - page_start, page_len);
+            if from == 0 && to == page_len {
+                self.pages[page] = Page::Uniform(state);
+            } else {
+                self.fill(page, from, to, state);
+            }
+            cur = page_start + to;
+        }
+    }
+    /// Extends the shadow to `new_len` bytes, marking the new bytes as `state`.
+    pub(crate) fn grow(&mut self, new_len: usize, state: MemState) {
+        assert!(new_len >= self.len, "shadow memory cannot shrink");
+        let tail = self.len % SHADOW_PAGE_SIZE;
+        if tail != 0 {
+            let page = self.pages.len() - 1;
+            let page_end = min(SHADOW_PAGE_SIZE, new_len - page * SHADOW_PAGE_SIZE);
+            self.materialize(page);
+            if let Page::Detailed { bytes, counts } = &mut self.pages[page] {
+                let mut grown = bytes.to_vec();
+                grown.resize(page_end, state);
+                *bytes = grown.into_boxed_slice();
+                counts[state as usize] += (page_end - tail) as u16;
+            }
+            self.try_collapse(page);
+        }
+        self.pages
+            .resize(new_len.div_ceil(SHADOW_PAGE_SIZE), Page::Uniform(state));
+        self.len = new_len;
+    }
+    /// Sets the bytes in `addr..addr + len` whose state satisfies `pred` to `state`.
+    pub(crate) fn replace(
+        &mut self,
+        addr: usize,
+        len: usize,
+        pred: impl Fn(MemState) -> bool,
+        state: MemState,
+    ) {
+        let mut runs = Vec::new();
+        let mut cur = addr;
+        self.for_each_run(addr, len, |run_state, run_len| {
+            if pred(run_state) {
+                runs.push((cur, run_len));
+            }
+            cur += run_len;
+        });
+        for (start, run_len) in runs {
+            self.set_range(start, run_len, state);
+        }
+    }
+    /// Copies the states of `src..src + len` to `dst..dst + len`; the ranges may overlap.
+    pub(crate) fn copy_within(&mut self, src: usize, dst: usize, len: usize) {
+        let mut runs: Vec<(MemState, usize)> = Vec::new();
+        self.for_each_run(src, len, |state, run_len| match runs.last_mut() {
+            Some((last, last_len)) if *last == state => *last_len += run_len,
+            _ => runs.push((state, run_len)),
+        });
+        let mut cur = dst;
+        for (state, run_len) in runs {
+            self.set_range(cur, run_len, state);
+            cur += run_len;
+        }
+    }
+    pub(crate) fn for_each_run(&self, addr: usize, len: usize, mut f: impl FnMut(MemState, usize)) {
+        assert!(addr + len <= self.len, "shadow access out of bounds");
+        let end = addr + len;
+        let mut cur = addr;
+        while cur < end {
+            let page = cur / SHADOW_PAGE_SIZE;
+            let page_start = page * SHADOW_PAGE_SIZE;
+            let from = cur - page_start;
+            let to = min(end - page_start, self.page_len(page));
+            match &self.pages[page] {
+                Page::Uniform(state) => f(*state, to - from),
+                Page::Detailed { bytes, .. } => {
+                    for chunk in bytes[from..to].chunk_by(|a, b| a == b) {
+                        f(chunk[0], chunk.len());
+                    }
+                }
+            }
+            cur = page_start + to;
+        }
+    }
+    /// Returns the first address in `addr..addr + len` whose state satisfies `pred`.
+    pub(crate) fn find(
+        &self,
+        addr: usize,
+        len: usize,
+        pred: impl Fn(MemState) -> bool,
+    ) -> Option<usize> {
+        assert!(addr + len <= self.len, "shadow access out of bounds");
+        let end = addr + len;
+        let mut cur = addr;
+        while cur < end {
+            let page = cur / SHADOW_PAGE_SIZE;
+            let page_start = page * SHADOW_PAGE_SIZE;
+            let from = cur - page_start;
+            let to = min(end - page_start, self.page_len(page));
+            match &self.pages[page] {
+                Page::Uniform(state) => {
+                    if pred(*state) {
+                        return Some(cur);
+                    }
+                }
+                Page::Detailed { bytes, .. } => {
+                    if let Some(i) = bytes[from..to].iter().position(|s| pred(*s)) {
+                        return Some(cur + i);
+                    }
+                }
+            }
+            cur = page_start + to;
+        }
+        None
+    }
+    fn page_len(&self, page: usize) -> usize {
+        min(SHADOW_PAGE_SIZE, self.len - page * SHADOW_PAGE_SIZE)
+    }
+    fn materialize(&mut self, page: usize) {
+        if let Page::Uniform(state) = self.pages[page] {
+            let page_len = self.page_len(page);
+            let mut counts = [0; NUM_STATES];
+            counts[state as usize] = page_len as u16;
+            self.pages[page] = Page::Detailed {
+                bytes: vec![state; page_len].into_boxed_slice(),
+                counts,
+            };
+        }
+    }
+    /// Sets bytes `from..to` of `page` to `state`, in time proportional to their number.
+    fn fill(&mut self, page: usize, from: usize, to: usize, state: MemState) {
+        self.materialize(page);
+        if let Page::Detailed { bytes, counts } = &mut self.pages[page] {
+            for byte in &mut bytes[from..to] {
+                counts[*byte as usize] -= 1;
+                *byte = state;
+            }
+            counts[state as usize] += (to - from) as u16;
+        }
+        self.try_collapse(page);
+    }
+    fn try_collapse(&mut self, page: usize) {
+        if let Page::Detailed { bytes, counts } = &self.pages[page] {
+            let first = bytes[0];
+            if counts[first as usize] as usize == bytes.len() {
+                self.pages[page] = Page::Uniform(first);
+            }
+        }
+    }
+    #[cfg(test)]
+    fn detailed_pages(&self) -> usize {
+        self.pages
+            .iter()
+            .filter(|p| matches!(p, Page::Detailed { .. }))
+            .count()
+    }
+}
+
+#[test]
+fn set_and_find_across_pages() {
+    let mut shadow = ShadowMemory::new(4 * SHADOW_PAGE_SIZE + 100, MemState::Unallocated);
+
+    shadow.set_range(
+        SHADOW_PAGE_SIZE - 8,
+        2 * SHADOW_PAGE_SIZE + 16,
+        MemState::ValidToWrite,
+    );
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE - 9), MemState::Unallocated);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE - 8), MemState::ValidToWrite);
+    assert_eq!(shadow.get(3 * SHADOW_PAGE_SIZE + 7), MemState::ValidToWrite);
+    assert_eq!(shadow.get(3 * SHADOW_PAGE_SIZE + 8), MemState::Unallocated);
+    assert_eq!(shadow.detailed_pages(), 2);
+    assert_eq!(
+        shadow.find(0, 4 * SHADOW_PAGE_SIZE, |s| s == MemState::ValidToWrite),
+        Some(SHADOW_PAGE_SIZE - 8)
+    );
+    assert_eq!(
+        shadow.find(SHADOW_PAGE_SIZE, 2 * SHADOW_PAGE_SIZE, |s| s
+            != MemState::ValidToWrite),
+        None
+    );
+    assert_eq!(
+        shadow.find(4 * SHADOW_PAGE_SIZE, 100, |s| s != MemState::Unallocated),
+        None
+    );
+}
+
+#[test]
+fn pages_collapse_when_uniform() {
+    let mut shadow = ShadowMemory::new(2 * SHADOW_PAGE_SIZE, MemState::Unallocated);
+
+    shadow.set_range(10, 20, MemState::ValidToReadWrite);
+    assert_eq!(shadow.detailed_pages(), 1);
+    shadow.set_range(10, 20, MemState::Unallocated);
+    assert_eq!(shadow.detailed_pages(), 0);
+
+    // a page written piecemeal collapses once the last piece is written
+    for addr in (0..SHADOW_PAGE_SIZE).step_by(4) {
+        assert_eq!(shadow.detailed_pages(), (addr != 0) as usize);
+        shadow.set_range(addr, 4, MemState::ValidToReadWrite);
+    }
+    assert_eq!(shadow.detailed_pages(), 0);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE - 1), MemState::ValidToReadWrite);
+}
+
+#[test]
+fn grow_partial_page() {
+    let mut shadow = ShadowMemory::new(SHADOW_PAGE_SIZE + 10, MemState::ValidToWrite);
+
+    shadow.grow(3 * SHADOW_PAGE_SIZE + 10, MemState::Unallocated);
+    assert_eq!(shadow.len(), 3 * SHADOW_PAGE_SIZE + 10);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE + 9), MemState::ValidToWrite);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE + 10), MemState::Unallocated);
+    assert_eq!(shadow.get(3 * SHADOW_PAGE_SIZE + 9), MemState::Unallocated);
+    assert_eq!(shadow.detailed_pages(), 1);
+}
+
+#[test]
+fn copy_overlapping() {
+    let mut shadow = ShadowMemory::new(3 * SHADOW_PAGE_SIZE, MemState::Unallocated);
+
+    shadow.set_range(SHADOW_PAGE_SIZE - 4, 8, MemState::ValidToWrite);
+    shadow.set_range(SHADOW_PAGE_SIZE - 2, 2, MemState::ValidToReadWrite);
+    shadow.copy_within(SHADOW_PAGE_SIZE - 4, SHADOW_PAGE_SIZE - 2, 8);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE - 3), MemState::ValidToWrite);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE - 2), MemState::ValidToWrite);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE - 1), MemState::ValidToWrite);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE), MemState::ValidToReadWrite);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE + 1), MemState::ValidToReadWrite);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE + 2), MemState::ValidToWrite);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE + 5), MemState::ValidToWrite);
+    assert_eq!(shadow.get(SHADOW_PAGE_SIZE + 6), MemState::Unallocated);
+}
+
+#[test]
+fn large_memory_is_cheap() {
+    let mut shadow = ShadowMemory::new(1 << 32, MemState::Unallocated);
+
+    shadow.set_range(0x1000_0000, 0x1000_0000, MemState::ValidToWrite);
+    assert_eq!(shadow.len(), 1 << 32);
+    assert_eq!(shadow.detailed_pages(), 0);
+    assert_eq!(shadow.get(0x1fff_ffff), MemState::ValidToWrite);
+}
@@ -0,0 +1,209 @@
+/*
+Stack frames: every move of the stack pointer to a lower address claims a frame for
+the innermost function reported through the call hooks, and the claimed bytes start
+out uninitialized. Moving the stack pointer back up pops the frames above it: their
+bytes become unallocated again, but are remembered as returned frames so that a later
+access through a dangling pointer is reported as a use after return rather than as a
+plain invalid access. Claiming the stack again forgets the returned frames it covers.
+
+Live and returned frames are disjoint and lie on either side of the stack pointer, so
+both are kept as vectors ordered by address.
+*/
+
+use crate::{AccessError, MemState, OriginKind, Valgrind};
+
+/// A frame of the shadow stack. `func` is the wasm function that claimed it, if the
+/// module reports its calls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackFrame {
+    pub addr: usize,
+    pub len: usize,
+    pub func: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct StackFrames {
+    live: Vec<StackFrame>,     // above the stack pointer, innermost (lowest) last
+    returned: Vec<StackFrame>, // below the stack pointer, nearest to it last
+}
+
+impl StackFrames {
+    /// Claims `addr..addr + len`, just below the stack pointer, for `func`.
+    fn claim(&mut self, addr: usize, len: usize, func: Option<u32>) {
+        while let Some(frame) = self.returned.last_mut() {
+            if frame.addr + frame.len <= addr {
+                break;
+            }
+            if frame.addr < addr {
+                frame.len = addr - frame.addr;
+                break;
+            }
+            self.returned.pop();
+        }
+        self.live.push(StackFrame { addr, len, func });
+    }
+    /// Pops the frames, or parts of frames, below `sp`, innermost first.
+    fn pop_to(&mut self, sp: usize) {
+        while let Some(frame) = self.live.last_mut() {
+            if frame.addr >= sp {
+                break;
+            }
+            let end = frame.addr + frame.len;
+            if end > sp {
+                self.returned.push(StackFrame {
+                    len: sp - frame.addr,
+                    ..frame.clone()
+                });
+                frame.len = end - sp;
+                frame.addr = sp;
+                break;
+            }
+            self.returned.push(self.live.pop().unwrap());
+        }
+    }
+    pub(crate) fn returned_at(&self, addr: usize) -> Option<&StackFrame> {
+        let index = self
+            .returned
+            .partition_point(|frame| frame.addr + frame.len <= addr);
+        self.returned.get(index).filter(|frame| frame.addr <= addr)
+    }
+}
+
+impl Valgrind {
+    /// The live frames of the shadow stack, outermost first.
+    pub fn stack_frames(&self) -> &[StackFrame] {
+        &self.frames.live
+    }
+    pub fn update_stack_pointer(&mut self, new_sp: usize) -> Result<(), AccessError> {
+        let result = self.move_stack_pointer(new_sp);
+        self.report(result)
+    }
+    fn move_stack_pointer(&mut self, new_sp: usize) -> Result<(), AccessError> {
+        if new_sp > self.max_stack_size {
+            return Err(AccessError::OutOfBounds {
+                addr: self.stack_pointer,
+                len: new_sp - self.stack_pointer,
+            });
+        } else if new_sp < self.stack_pointer {
+            let len = self.stack_pointer - new_sp;
+            self.metadata.set_range(new_sp, len, MemState::ValidToWrite);
+            let func = self.call_stack.last().copied();
+            self.frames.claim(new_sp, len, func);
+            self.record_origin(new_sp, len, Some(OriginKind::Stack));
+        } else {
+            self.metadata.set_range(
+                self.stack_pointer,
+                new_sp - self.stack_pointer,
+                MemState::Unallocated,
+            );
+            self.frames.pop_to(new_sp);
+        }
+        self.stack_pointer = new_sp;
+        Ok(())
+    }
+}
+
+#[test]
+fn frames_claim_and_return() {
+    let mut frames = StackFrames::default();
+    frames.claim(0x3f0, 0x10, Some(1));
+    frames.claim(0x3e0, 0x10, Some(2));
+    frames.claim(0x3d8, 0x8, Some(3));
+
+    // popping into the middle of a frame splits it
+    frames.pop_to(0x3e8);
+    assert_eq!(frames.live.len(), 2);
+    assert_eq!(frames.live[1].addr, 0x3e8);
+    assert_eq!(
+        frames.returned_at(0x3e4).and_then(|frame| frame.func),
+        Some(2)
+    );
+    assert_eq!(
+        frames.returned_at(0x3d8).and_then(|frame| frame.func),
+        Some(3)
+    );
+    assert_eq!(frames.returned_at(0x3e8), None);
+
+    // claiming the stack again forgets the returned frames it covers
+    frames.claim(0x3e4, 0x4, Some(4));
+    assert_eq!(frames.returned_at(0x3e4), None);
+    assert_eq!(frames.returned_at(0x3e0).map(|frame| frame.len), Some(4));
+    frames.pop_to(0x400);
+    assert!(frames.live.is_empty());
+    assert_eq!(
+        frames.returned_at(0x3f8).and_then(|frame| frame.func),
+        Some(1)
+    );
+}
+
+#[test]
+fn use_after_return() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+
+    valgrind_state.enter_function(5);
+    assert!(valgrind_state.update_stack_pointer(1008).is_ok());
+    assert_eq!(
+        valgrind_state.stack_frames(),
+        [StackFrame {
+            addr: 1008,
+            len: 16,
+            func: Some(5)
+        }]
+    );
+    // locals start out uninitialized
+    assert!(matches!(
+        valgrind_state.read(1008, 4),
+        Err(AccessError::InvalidRead {
+            state: MemState::ValidToWrite,
+            ..
+        })
+    ));
+    assert!(valgrind_state.write(1008, 8).is_ok());
+    assert!(valgrind_state.read(1008, 8).is_ok());
+    assert!(valgrind_state.update_stack_pointer(1024).is_ok());
+    valgrind_state.exit_function();
+
+    let err = valgrind_state.read(1012, 4).unwrap_err();
+    assert_eq!(
+        err,
+        AccessError::UseAfterReturn {
+            addr: 1012,
+            len: 4,
+            frame: StackFrame {
+                addr: 1008,
+                len: 16,
+                func: Some(5)
+            }
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "Use after return of size 4 at 0x3f4, 4 bytes inside a returned stack frame of size 16"
+    );
+    assert!(matches!(
+        valgrind_state.write(1008, 4),
+        Err(AccessError::UseAfterReturn { .. })
+    ));
+    assert!(matches!(
+        valgrind_state.read(900, 4),
+        Err(AccessError::InvalidRead { .. })
+    ));
+}
+
+#[test]
+fn access_straddling_a_returned_frame() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+
+    assert!(valgrind_state.update_stack_pointer(1008).is_ok());
+    assert!(valgrind_state.update_stack_pointer(1024).is_ok());
+    assert!(valgrind_state.static_data(1000, 8).is_ok());
+    let err = valgrind_state.read(1004, 8).unwrap_err();
+    assert!(matches!(
+        err,
+        AccessError::UseAfterReturn { addr: 1004, .. }
+    ));
+    assert_eq!(
+        err.to_string(),
+        "Use after return of size 8 at 0x3ec, 4 bytes before a returned stack frame of size 16"
+    );
+}
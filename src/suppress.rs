@@ -0,0 +1,317 @@
+/*
+Suppressions silence known, accepted errors. The file format follows valgrind's:
+
+    {
+       libfoo-leaky-free
+       Memcheck:InvalidFree
+       fun:foo_*
+       ...
+       fun:main
+       addr:0x10000-0x20000
+    }
+
+The first line of an entry names it and the second gives the error kind, as returned
+by `AccessError::kind` (a `tool:` prefix is ignored). Frames run from the innermost
+outwards and must match a prefix of the error's call stack: `fun:` matches one frame by
+function name, `...` any number of frames. `addr:` restricts the entry to errors whose
+address lies in the half-open range. Kinds and function names may use the `*` and `?`
+wildcards; `#` starts a comment line.
+*/
+
+use crate::{AccessError, ErrorSummary};
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SuppressionError {
+    /// An entry is missing its name or kind, or has a line that is not a frame or range.
+    Expected {
+        line: usize,
+        what: &'static str,
+    },
+    BadRange {
+        line: usize,
+    },
+    /// `line` is that of the entry's opening brace.
+    Unterminated {
+        line: usize,
+    },
+}
+
+impl fmt::Display for SuppressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SuppressionError::Expected { line, what } => {
+                write!(f, "line {}: expected {}", line, what)
+            }
+            SuppressionError::BadRange { line } => {
+                write!(
+                    f,
+                    "line {}: expected an address range like 0x100-0x200",
+                    line
+                )
+            }
+            SuppressionError::Unterminated { line } => {
+                write!(f, "line {}: suppression is missing its closing brace", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SuppressionError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Frame {
+    Fun(String),
+    Any, // `...`
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Suppression {
+    name: String,
+    kind: String,
+    frames: Vec<Frame>,
+    addrs: Option<Range<usize>>,
+    fired: usize,
+}
+
+impl Suppression {
+    fn matches(&self, error: &AccessError, frames: &[&str]) -> bool {
+        wildcard(self.kind.as_bytes(), error.kind().as_bytes())
+            && frames_match(&self.frames, frames)
+            && match (&self.addrs, error.addr()) {
+                (None, _) => true,
+                (Some(range), Some(addr)) => range.contains(&addr),
+                (Some(_), None) => false,
+            }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Suppressions {
+    list: Vec<Suppression>,
+}
+
+impl Suppressions {
+    pub fn parse(text: &str) -> Result<Suppressions, SuppressionError> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        let mut suppressions = Suppressions::default();
+        while let Some((open, line)) = lines.next() {
+            if line != "{" {
+                return Err(SuppressionError::Expected {
+                    line: open,
+                    what: "'{'",
+                });
+            }
+            let mut next = |what| match lines.next() {
+                Some((_, "}")) | None => Err(SuppressionError::Expected { line: open, what }),
+                Some((_, line)) => Ok(line),
+            };
+            let name = next("a suppression name")?.to_string();
+            let kind = next("an error kind")?;
+            let kind = kind.rsplit(':').next().unwrap_or(kind).to_string();
+            let mut suppression = Suppression {
+                name,
+                kind,
+                frames: Vec::new(),
+                addrs: None,
+                fired: 0,
+            };
+            loop {
+                let (number, line) = lines
+                    .next()
+                    .ok_or(SuppressionError::Unterminated { line: open })?;
+                if line == "}" {
+                    break;
+                } else if line == "..." {
+                    suppression.frames.push(Frame::Any);
+                } else if let Some(pattern) = line.strip_prefix("fun:") {
+                    suppression.frames.push(Frame::Fun(pattern.to_string()));
+                } else if let Some(range) = line.strip_prefix("addr:") {
+                    let range =
+                        parse_range(range).ok_or(SuppressionError::BadRange { line: number })?;
+                    suppression.addrs = Some(range);
+                } else {
+                    return Err(SuppressionError::Expected {
+                        line: number,
+                        what: "'fun:', '...', 'addr:' or '}'",
+                    });
+                }
+            }
+            suppressions.list.push(suppression);
+        }
+        Ok(suppressions)
+    }
+    /// Adds the entries of another file.
+    pub fn extend(&mut self, other: Suppressions) {
+        self.list.extend(other.list);
+    }
+    /// Every entry's name with the number of errors it suppressed, in file order.
+    pub fn fired(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.list
+            .iter()
+            .map(|suppression| (suppression.name.as_str(), suppression.fired))
+    }
+    /// Suppressed errors, and the number of entries that suppressed them.
+    pub fn summary(&self) -> ErrorSummary {
+        ErrorSummary {
+            errors: self.list.iter().map(|suppression| suppression.fired).sum(),
+            contexts: self.list.iter().filter(|s| s.fired > 0).count(),
+        }
+    }
+    /// Whether the first matching entry suppresses `error`, raised with the call stack
+    /// `frames` (innermost first).
+    pub(crate) fn suppress(&mut self, error: &AccessError, frames: &[&str]) -> bool {
+        match self.list.iter_mut().find(|s| s.matches(error, frames)) {
+            Some(suppression) => {
+                suppression.fired += 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn parse_range(text: &str) -> Option<Range<usize>> {
+    let number = |text: &str| match text.trim().strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.trim().parse().ok(),
+    };
+    let (start, end) = text.split_once('-')?;
+    Some(number(start)?..number(end)?)
+}
+
+fn frames_match(patterns: &[Frame], frames: &[&str]) -> bool {
+    match patterns.split_first() {
+        None => true,
+        Some((Frame::Any, rest)) => {
+            (0..=frames.len()).any(|skip| frames_match(rest, &frames[skip..]))
+        }
+        Some((Frame::Fun(pattern), rest)) => match frames.split_first() {
+            Some((frame, outer)) => {
+                wildcard(pattern.as_bytes(), frame.as_bytes()) && frames_match(rest, outer)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Matches `*` and `?` globs in O(pattern × text) time: on a mismatch only the most
+/// recent `*` needs to absorb another byte, as earlier stars cannot help more.
+fn wildcard(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut star = None; // pattern index after the last `*`, and the text it resumes at
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, t));
+            }
+            Some(c) if *c == b'?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((after, resume)) => {
+                    p = after;
+                    t = resume + 1;
+                    star = Some((after, t));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[test]
+fn parses_and_matches() {
+    let mut suppressions = Suppressions::parse(
+        "# accepted reports
+        {
+           libfoo-free
+           Memcheck:InvalidFree
+           fun:foo_*
+           ...
+           fun:main
+        }
+        {
+           low-reads
+           Invalid?ead
+           addr:0x1000-0x2000
+        }",
+    )
+    .unwrap();
+    let free = AccessError::InvalidFree {
+        addr: 0x3000,
+        block: None,
+        freed_block: None,
+    };
+    let read = |addr| AccessError::OutOfBounds { addr, len: 4 };
+
+    assert!(suppressions.suppress(&free, &["foo_release", "main"]));
+    assert!(suppressions.suppress(&free, &["foo_release", "bar", "baz", "main", "_start"]));
+    assert!(!suppressions.suppress(&free, &["bar", "foo_release", "main"]));
+    assert!(!suppressions.suppress(&free, &["foo_release"]));
+    assert!(!suppressions.suppress(&read(0x1000), &[]));
+
+    let read = |addr| AccessError::InvalidRead {
+        addr,
+        len: 4,
+        bad_addr: addr,
+        state: crate::MemState::Unallocated,
+        block: None,
+        origin: None,
+    };
+    assert!(suppressions.suppress(&read(0x1ffc), &["anything"]));
+    assert!(!suppressions.suppress(&read(0x2000), &[]));
+
+    assert_eq!(
+        suppressions.fired().collect::<Vec<_>>(),
+        [("libfoo-free", 2), ("low-reads", 1)]
+    );
+    assert_eq!(
+        suppressions.summary(),
+        ErrorSummary {
+            errors: 3,
+            contexts: 2
+        }
+    );
+}
+
+#[test]
+fn rejects_malformed_files() {
+    assert_eq!(
+        Suppressions::parse("{\n  name\n}"),
+        Err(SuppressionError::Expected {
+            line: 1,
+            what: "an error kind"
+        })
+    );
+    assert_eq!(
+        Suppressions::parse("{\n  name\n  InvalidRead\n  addr:0x10\n}"),
+        Err(SuppressionError::BadRange { line: 4 })
+    );
+    assert_eq!(
+        Suppressions::parse("{\n  name\n  InvalidRead\n  fun:f"),
+        Err(SuppressionError::Unterminated { line: 1 })
+    );
+    assert!(Suppressions::parse("name").is_err());
+}
+
+#[test]
+fn wildcards_match_without_backtracking_blowup() {
+    assert!(wildcard(b"fun:*_free", b"fun:foo_bar_free"));
+    assert!(wildcard(b"a*b?d*", b"axxbcd"));
+    assert!(wildcard(b"**", b""));
+    assert!(!wildcard(b"a*b", b"a"));
+    assert!(!wildcard(b"?", b""));
+    let text = [b'a'; 64];
+    let mut pattern = b"*a".repeat(20);
+    pattern.push(b'b');
+    assert!(!wildcard(&pattern, &text));
+}
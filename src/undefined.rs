@@ -0,0 +1,329 @@
+/*
+By default a load of uninitialized bytes is an error. Memcheck instead tracks the
+definedness of values and only complains when an undefined value is used, so copying a
+struct with uninitialized padding is fine. `UndefinedChecks::OnUse` approximates this:
+loads only need allocated memory, and copies (`memory.copy`, and loads whose value is
+stored unchanged, which the instrumentation reports as moves) give each destination byte
+the state of its source byte. Undefined bytes are reported at the enabled use points:
+when a host call reads them, or when they are copied into a buffer the host reads.
+
+Modules instrumented with value shadows also carry definedness through wasm values: a
+loaded value is undefined if any of its bytes is, arithmetic on an undefined operand
+gives an undefined result, and storing an undefined value leaves its bytes undefined.
+The instrumentation reports undefined values where they decide a branch, select a
+`call_indirect` target or address memory.
+*/
+
+use crate::{AccessError, MemState, Valgrind};
+use std::ops::Range;
+
+/// Where undefined bytes are reported in `UndefinedChecks::OnUse` mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UsePoint {
+    /// Memory read by a host function, see `Valgrind::host_read`.
+    HostCall,
+    /// Memory copied into a buffer registered with `Valgrind::add_host_buffer`.
+    HostBuffer,
+}
+
+/// Where a module instrumented with value shadows reports undefined values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueUse {
+    /// The condition of `br_if`, `if`, `select` or the index of `br_table`.
+    Branch,
+    /// The table index of `call_indirect`.
+    IndirectCall,
+    /// The address operand of a load or store.
+    Address,
+}
+
+impl ValueUse {
+    /// The `point` argument of the `undefined_value` hook.
+    pub fn code(self) -> u32 {
+        match self {
+            ValueUse::Branch => 0,
+            ValueUse::IndirectCall => 1,
+            ValueUse::Address => 2,
+        }
+    }
+    pub fn from_code(code: u32) -> Option<ValueUse> {
+        match code {
+            0 => Some(ValueUse::Branch),
+            1 => Some(ValueUse::IndirectCall),
+            2 => Some(ValueUse::Address),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UsePoints {
+    pub host_calls: bool,
+    pub host_buffers: bool,
+    pub branches: bool,
+    pub indirect_calls: bool,
+    pub addresses: bool,
+}
+
+impl Default for UsePoints {
+    fn default() -> UsePoints {
+        UsePoints {
+            host_calls: true,
+            host_buffers: true,
+            branches: true,
+            indirect_calls: true,
+            addresses: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum UndefinedChecks {
+    /// Every load of uninitialized bytes is an error.
+    #[default]
+    OnLoad,
+    /// Undefined bytes are copied along and only reported at the given use points.
+    OnUse(UsePoints),
+}
+
+impl Valgrind {
+    pub fn set_undefined_checks(&mut self, checks: UndefinedChecks) {
+        self.undefined_checks = checks;
+    }
+    pub fn undefined_checks(&self) -> UndefinedChecks {
+        self.undefined_checks
+    }
+    /// Registers `addr..addr + len` as memory the host reads on its own, such as a buffer
+    /// shared with a device; copying undefined bytes into it is a use.
+    pub fn add_host_buffer(&mut self, addr: usize, len: usize) {
+        self.host_buffers.push(addr..addr + len);
+    }
+    /// Forgets the host buffer starting at `addr`.
+    pub fn remove_host_buffer(&mut self, addr: usize) {
+        self.host_buffers.retain(|buffer| buffer.start != addr);
+    }
+    /// Models a host function reading `addr..addr + len`, e.g. a buffer passed to `fd_write`.
+    /// The bytes must be initialized, unless host calls are not a use point.
+    pub fn host_read(&mut self, addr: usize, len: usize) -> Result<(), AccessError> {
+        let result = match self.undefined_checks {
+            UndefinedChecks::OnUse(points) if !points.host_calls => self.check_read(addr, len),
+            UndefinedChecks::OnUse(_) => self
+                .check_read(addr, len)
+                .and_then(|()| self.check_defined(UsePoint::HostCall, addr, len)),
+            UndefinedChecks::OnLoad => self.check_read(addr, len),
+        };
+        self.report(result)
+    }
+    /// Models a load of `len` bytes at `src` whose value is stored unchanged at `dst`: a
+    /// copy when checking on use, else a read followed by a write.
+    pub fn move_value(&mut self, dst: usize, src: usize, len: usize) -> Result<(), AccessError> {
+        match self.undefined_checks {
+            UndefinedChecks::OnUse(_) => self.copy(dst, src, len),
+            UndefinedChecks::OnLoad => {
+                self.read(src, len)?;
+                self.write(dst, len)
+            }
+        }
+    }
+    /// Models a load of a value whose definedness the module tracks; returns whether any
+    /// of its bytes is undefined. Only bytes copied along by `OnUse` checks can be.
+    pub fn load_value(&mut self, addr: usize, len: usize) -> Result<bool, AccessError> {
+        self.read(addr, len)?;
+        let undefined = |state| state == MemState::ValidToWrite;
+        Ok(matches!(self.undefined_checks, UndefinedChecks::OnUse(_))
+            && self.is_in_bounds(addr, len)
+            && self.metadata.find(addr, len, undefined).is_some())
+    }
+    /// Models a store of a value whose definedness the module tracks: the bytes of an
+    /// undefined value stay undefined when checking on use.
+    pub fn store_value(
+        &mut self,
+        addr: usize,
+        len: usize,
+        undefined: bool,
+    ) -> Result<(), AccessError> {
+        self.write(addr, len)?;
+        if undefined
+            && matches!(self.undefined_checks, UndefinedChecks::OnUse(_))
+            && self.is_in_bounds(addr, len)
+        {
+            let written = |state| state == MemState::ValidToReadWrite;
+            self.metadata
+                .replace(addr, len, written, MemState::ValidToWrite);
+            // the value's origin was lost in a wasm local
+            self.record_origin(addr, len, None);
+        }
+        Ok(())
+    }
+    /// Reports an undefined value reaching `point`, if that is a use point.
+    pub fn undefined_value(&mut self, point: ValueUse) -> Result<(), AccessError> {
+        let enabled = match self.undefined_checks {
+            UndefinedChecks::OnUse(points) => match point {
+                ValueUse::Branch => points.branches,
+                ValueUse::IndirectCall => points.indirect_calls,
+                ValueUse::Address => points.addresses,
+            },
+            UndefinedChecks::OnLoad => false,
+        };
+        if enabled {
+            self.report(Err(AccessError::UndefinedValue { point }))
+        } else {
+            Ok(())
+        }
+    }
+    /// Whether a load may read bytes in `state`.
+    pub(crate) fn is_loadable(&self, state: MemState) -> bool {
+        match self.undefined_checks {
+            UndefinedChecks::OnLoad => state == MemState::ValidToReadWrite,
+            UndefinedChecks::OnUse(_) => {
+                matches!(state, MemState::ValidToWrite | MemState::ValidToReadWrite)
+            }
+        }
+    }
+    /// Checks the bytes a copy of `len` bytes from `src` to `dst` writes into host buffers.
+    pub(crate) fn check_copy_to_host(
+        &self,
+        dst: usize,
+        src: usize,
+        len: usize,
+    ) -> Result<(), AccessError> {
+        let UndefinedChecks::OnUse(points) = self.undefined_checks else {
+            return Ok(());
+        };
+        if !points.host_buffers {
+            return Ok(());
+        }
+        for buffer in &self.host_buffers {
+            let Range { start, end } = buffer.start.max(dst)..buffer.end.min(dst + len);
+            if start < end {
+                let bad = self.metadata.find(src + start - dst, end - start, |state| {
+                    state == MemState::ValidToWrite
+                });
+                if let Some(bad_addr) = bad {
+                    return Err(self.uninitialized_use(UsePoint::HostBuffer, src, len, bad_addr));
+                }
+            }
+        }
+        Ok(())
+    }
+    fn check_defined(&self, point: UsePoint, addr: usize, len: usize) -> Result<(), AccessError> {
+        let undefined = |state| state == MemState::ValidToWrite;
+        match self.metadata.find(addr, len, undefined) {
+            Some(bad_addr) => Err(self.uninitialized_use(point, addr, len, bad_addr)),
+            None => Ok(()),
+        }
+    }
+    fn uninitialized_use(
+        &self,
+        point: UsePoint,
+        addr: usize,
+        len: usize,
+        bad_addr: usize,
+    ) -> AccessError {
+        AccessError::UninitializedUse {
+            point,
+            addr,
+            len,
+            bad_addr,
+            block: self.nearest_block(bad_addr),
+            origin: self.origin_at(bad_addr),
+        }
+    }
+}
+
+#[test]
+fn undefined_bytes_are_copied_until_used() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+    valgrind_state.set_undefined_checks(UndefinedChecks::OnUse(UsePoints::default()));
+
+    // a struct whose last 4 bytes are padding is copied field by field
+    assert!(valgrind_state.malloc(0x1000, 16).is_ok());
+    assert!(valgrind_state.malloc(0x2000, 16).is_ok());
+    assert!(valgrind_state.write(0x1000, 12).is_ok());
+    assert!(valgrind_state.move_value(0x2000, 0x1000, 8).is_ok());
+    assert!(valgrind_state.move_value(0x2008, 0x1008, 8).is_ok());
+    assert!(valgrind_state.read(0x200c, 4).is_ok());
+    assert!(valgrind_state.host_read(0x2000, 12).is_ok());
+    assert!(matches!(
+        valgrind_state.host_read(0x2000, 16),
+        Err(AccessError::UninitializedUse {
+            point: UsePoint::HostCall,
+            bad_addr: 0x200c,
+            ..
+        })
+    ));
+    assert!(valgrind_state.free(0x1000).is_ok());
+    assert!(valgrind_state.read(0x1000, 4).is_err());
+
+    valgrind_state.set_undefined_checks(UndefinedChecks::OnLoad);
+    assert!(matches!(
+        valgrind_state.move_value(0x2008, 0x2008, 8),
+        Err(AccessError::InvalidRead {
+            bad_addr: 0x200c,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn copies_into_host_buffers() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+    valgrind_state.set_undefined_checks(UndefinedChecks::OnUse(UsePoints::default()));
+    valgrind_state.add_host_buffer(0x3000, 16);
+    assert!(valgrind_state.static_data(0x3000, 16).is_ok());
+
+    assert!(valgrind_state.malloc(0x1000, 16).is_ok());
+    assert!(valgrind_state.write(0x1000, 8).is_ok());
+    assert!(valgrind_state.copy(0x2ff8, 0x1000, 16).is_err());
+    assert!(valgrind_state.malloc(0x2000, 16).is_ok());
+    assert!(valgrind_state.copy(0x2000, 0x1000, 16).is_ok());
+    assert!(valgrind_state.copy(0x3000, 0x1000, 8).is_ok());
+    assert!(matches!(
+        valgrind_state.copy(0x3004, 0x1000, 12),
+        Err(AccessError::UninitializedUse {
+            point: UsePoint::HostBuffer,
+            bad_addr: 0x1008,
+            ..
+        })
+    ));
+
+    valgrind_state.set_undefined_checks(UndefinedChecks::OnUse(UsePoints {
+        host_buffers: false,
+        ..UsePoints::default()
+    }));
+    assert!(valgrind_state.copy(0x3004, 0x1000, 12).is_ok());
+    valgrind_state.remove_host_buffer(0x3000);
+    valgrind_state.set_undefined_checks(UndefinedChecks::OnUse(UsePoints::default()));
+    assert!(valgrind_state.copy(0x3000, 0x1000, 16).is_ok());
+}
+
+#[test]
+fn undefined_values_through_memory() {
+    let mut valgrind_state = Valgrind::new(640 * 1024, 1024);
+    valgrind_state.set_undefined_checks(UndefinedChecks::OnUse(UsePoints::default()));
+
+    assert!(valgrind_state.malloc(0x1000, 16).is_ok());
+    assert_eq!(valgrind_state.store_value(0x1000, 4, false), Ok(()));
+    assert_eq!(valgrind_state.load_value(0x1000, 4), Ok(false));
+    assert_eq!(valgrind_state.load_value(0x1002, 4), Ok(true));
+    // an undefined value stored elsewhere stays undefined
+    assert_eq!(valgrind_state.store_value(0x1008, 4, true), Ok(()));
+    assert_eq!(valgrind_state.load_value(0x1008, 1), Ok(true));
+    assert!(valgrind_state.host_read(0x1008, 4).is_err());
+    assert!(valgrind_state.load_value(0x1010, 4).is_err());
+    assert_eq!(
+        valgrind_state.undefined_value(ValueUse::IndirectCall),
+        Err(AccessError::UndefinedValue {
+            point: ValueUse::IndirectCall
+        })
+    );
+
+    valgrind_state.set_undefined_checks(UndefinedChecks::OnUse(UsePoints {
+        branches: false,
+        ..UsePoints::default()
+    }));
+    assert_eq!(valgrind_state.undefined_value(ValueUse::Branch), Ok(()));
+    valgrind_state.set_undefined_checks(UndefinedChecks::OnLoad);
+    assert_eq!(valgrind_state.undefined_value(ValueUse::Address), Ok(()));
+    assert_eq!(valgrind_state.load_value(0x1000, 4), Ok(false));
+}
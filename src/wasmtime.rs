@@ -0,0 +1,691 @@
+/*
+Runs instrumented modules under Wasmtime. `add_to_linker` defines every
+`wasm_valgrind` hook; each store keeps a `ValgrindCtx` in its data, which creates
+the `Monitor` on the first hook call (sized from the instance's exported `memory`),
+grows it along with that memory and applies the `ErrorPolicy` to every error.
+Collecting runs the `Valgrind` in continue-on-error mode, so repeats of an error at
+the same site are folded together.
+
+A context shadows the memory of a single instance, the first to call a hook.
+Instrumented modules instantiated together need a store each: a hook called from any
+other instance of the store fails, trapping that instance, rather than checking its
+accesses against the wrong shadow memory.
+*/
+
+use crate::instrument::{self, Layout, Symbols};
+use crate::{AccessError, ErrorLog, Monitor, Suppressions, UndefinedChecks, WASM_PAGE_SIZE};
+use ::wasmtime::{AsContextMut, Caller, Extern, Instance, Linker, Memory, Val};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorPolicy {
+    /// Stop the guest with a trap whose error is the `AccessError`.
+    Trap,
+    /// Log the error and let the guest carry on.
+    Collect,
+}
+
+pub struct ValgrindCtx {
+    layout: Layout,
+    policy: ErrorPolicy,
+    monitor: Option<Monitor>,
+    mem_size: usize,
+    memory: Option<Option<Memory>>, // the exported memory of the instance shadowed
+    suppressions: Option<Suppressions>, // handed to the monitor once created
+    symbols: Symbols,
+    stack_depth: Option<usize>,
+    undefined_checks: UndefinedChecks,
+    track_origins: bool,
+}
+
+impl ValgrindCtx {
+    pub fn new(layout: Layout, policy: ErrorPolicy) -> ValgrindCtx {
+        ValgrindCtx {
+            layout,
+            policy,
+            monitor: None,
+            mem_size: 0,
+            memory: None,
+            suppressions: None,
+            symbols: Symbols::default(),
+            stack_depth: None,
+            undefined_checks: UndefinedChecks::OnLoad,
+            track_origins: false,
+        }
+    }
+    /// Sets `Valgrind::set_stack_depth` on the monitor once created.
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack_depth = Some(depth);
+    }
+    /// Sets `Valgrind::set_undefined_checks` on the monitor once created. Checking on use
+    /// needs a module instrumented with moves or value shadows.
+    pub fn set_undefined_checks(&mut self, checks: UndefinedChecks) {
+        self.undefined_checks = checks;
+    }
+    /// Sets `Valgrind::set_track_origins` on the monitor once created.
+    pub fn set_track_origins(&mut self, enabled: bool) {
+        self.track_origins = enabled;
+    }
+    /// Drops errors matching `suppressions`; `symbols` of the original module name the
+    /// functions they are matched against.
+    pub fn set_suppressions(&mut self, suppressions: Suppressions, symbols: Symbols) {
+        self.suppressions = Some(suppressions);
+        self.symbols = symbols;
+    }
+    /// The monitor, once the guest has made its first hook call.
+    pub fn monitor(&self) -> Option<&Monitor> {
+        self.monitor.as_ref()
+    }
+    pub fn monitor_mut(&mut self) -> Option<&mut Monitor> {
+        self.monitor.as_mut()
+    }
+    /// Errors logged under `ErrorPolicy::Collect`.
+    pub fn error_log(&self) -> Option<&ErrorLog> {
+        self.monitor()?.valgrind().error_log()
+    }
+    /// Returns the monitor for a memory of `mem_size` bytes, creating or growing it as needed.
+    fn sync(&mut self, mem_size: usize) -> Result<&mut Monitor, AccessError> {
+        match &mut self.monitor {
+            None => {
+                let mut monitor = Monitor::with_layout(mem_size, &self.layout)?;
+                monitor
+                    .valgrind_mut()
+                    .set_continue_on_error(self.policy == ErrorPolicy::Collect);
+                monitor
+                    .valgrind_mut()
+                    .set_undefined_checks(self.undefined_checks);
+                monitor.valgrind_mut().set_track_origins(self.track_origins);
+                if let Some(depth) = self.stack_depth {
+                    monitor.valgrind_mut().set_stack_depth(depth);
+                }
+                if let Some(suppressions) = self.suppressions.take() {
+                    let valgrind = monitor.valgrind_mut();
+                    valgrind.set_suppressions(suppressions);
+                    valgrind.set_symbols(std::mem::take(&mut self.symbols));
+                }
+                self.monitor = Some(monitor);
+            }
+            Some(monitor) if mem_size > self.mem_size => {
+                let delta_pages = (mem_size - self.mem_size) / WASM_PAGE_SIZE;
+                monitor.valgrind_mut().grow(delta_pages)?;
+            }
+            Some(_) => {}
+        }
+        self.mem_size = mem_size;
+        Ok(self.monitor.as_mut().unwrap())
+    }
+}
+
+fn check<T, R>(
+    caller: &mut Caller<'_, T>,
+    get: impl Fn(&mut T) -> &mut ValgrindCtx,
+    f: impl FnOnce(&mut Monitor) -> Result<R, AccessError>,
+) -> anyhow::Result<R> {
+    let memory = exported_memory(caller);
+    match get(caller.data_mut()).memory {
+        None => get(caller.data_mut()).memory = Some(memory),
+        // distinct memories never share their base address
+        Some(shadowed) => {
+            let base = |memory: Option<Memory>| memory.map(|memory| memory.data_ptr(&caller));
+            if base(shadowed) != base(memory) {
+                anyhow::bail!("hooks of a ValgrindCtx called from a second instance");
+            }
+        }
+    }
+    let mem_size = memory.map_or(0, |memory| memory.data_size(&caller));
+    // errors that reach here trap; under `Collect` the monitor has already logged the rest
+    Ok(get(caller.data_mut()).sync(mem_size).and_then(f)?)
+}
+
+fn exported_memory<T>(caller: &mut Caller<'_, T>) -> Option<Memory> {
+    match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => Some(memory),
+        _ => None,
+    }
+}
+
+/// The values of the i32 and i64 globals `instance` exports, including those exported
+/// by instrumentation, that fit a 32-bit pointer: the roots `Valgrind::leak_check`
+/// cannot find in linear memory.
+pub fn global_roots(instance: &Instance, mut store: impl AsContextMut) -> Vec<usize> {
+    let globals: Vec<_> = instance
+        .exports(&mut store)
+        .filter_map(|export| export.into_global())
+        .collect();
+    globals
+        .into_iter()
+        .filter_map(|global| match global.get(&mut store) {
+            Val::I32(value) => Some(value as u32 as usize),
+            Val::I64(value) => u32::try_from(value).ok().map(|value| value as usize),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Defines the hooks imported by instrumented modules, reporting to the `ValgrindCtx`
+/// returned by `get`.
+pub fn add_to_linker<T: 'static>(
+    linker: &mut Linker<T>,
+    get: impl Fn(&mut T) -> &mut ValgrindCtx + Send + Sync + Copy + 'static,
+) -> anyhow::Result<()> {
+    use instrument::*;
+    linker.func_wrap(
+        HOOK_MODULE,
+        LOAD_HOOK,
+        move |mut caller: Caller<'_, T>, addr: u32, offset: u32, len: u32, site: u32| {
+            check(&mut caller, get, |m| m.load(addr, offset, len, site))
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        STORE_HOOK,
+        move |mut caller: Caller<'_, T>, addr: u32, offset: u32, len: u32, site: u32| {
+            check(&mut caller, get, |m| m.store(addr, offset, len, site))
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        LOAD_VALUE_HOOK,
+        move |mut caller: Caller<'_, T>, addr: u32, offset: u32, len: u32, site: u32| {
+            check(&mut caller, get, |m| m.load_value(addr, offset, len, site))
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        STORE_VALUE_HOOK,
+        move |mut caller: Caller<'_, T>,
+              addr: u32,
+              offset: u32,
+              len: u32,
+              undefined: u32,
+              site: u32| {
+            check(&mut caller, get, |m| {
+                m.store_value(addr, offset, len, undefined, site)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        UNDEFINED_VALUE_HOOK,
+        move |mut caller: Caller<'_, T>, point: u32, site: u32| {
+            check(&mut caller, get, |m| m.undefined_value(point, site))
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        COPY_HOOK,
+        move |mut caller: Caller<'_, T>, dst: u32, src: u32, len: u32, site: u32| {
+            check(&mut caller, get, |m| m.copy(dst, src, len, site))
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        MOVE_HOOK,
+        move |mut caller: Caller<'_, T>,
+              dst: u32,
+              dst_offset: u32,
+              src: u32,
+              src_offset: u32,
+              len: u32,
+              site: u32| {
+            check(&mut caller, get, |m| {
+                m.move_value(dst, dst_offset, src, src_offset, len, site)
+            })
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        FILL_HOOK,
+        move |mut caller: Caller<'_, T>, dst: u32, len: u32, site: u32| {
+            check(&mut caller, get, |m| m.fill(dst, len, site))
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        ALLOC_ENTER_HOOK,
+        move |mut caller: Caller<'_, T>| {
+            check(&mut caller, get, |m| {
+                m.alloc_enter();
+                Ok(())
+            })
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        ALLOC_EXIT_HOOK,
+        move |mut caller: Caller<'_, T>| {
+            check(&mut caller, get, |m| {
+                m.alloc_exit();
+                Ok(())
+            })
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        MALLOC_HOOK,
+        move |mut caller: Caller<'_, T>, ret: u32, size: u32| {
+            check(&mut caller, get, |m| m.malloc(ret, size))
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        FREE_HOOK,
+        move |mut caller: Caller<'_, T>, ptr: u32| check(&mut caller, get, |m| m.free(ptr)),
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        CALLOC_HOOK,
+        move |mut caller: Caller<'_, T>, ret: u32, nmemb: u32, size: u32| {
+            check(&mut caller, get, |m| m.calloc(ret, nmemb, size))
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        REALLOC_HOOK,
+        move |mut caller: Caller<'_, T>, ret: u32, ptr: u32, size: u32| {
+            check(&mut caller, get, |m| m.realloc(ret, ptr, size))
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        ALIGNED_ALLOC_HOOK,
+        move |mut caller: Caller<'_, T>, ret: u32, align: u32, size: u32| {
+            check(&mut caller, get, |m| m.aligned_alloc(ret, align, size))
+        },
+    )?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        ENTER_HOOK,
+        move |mut caller: Caller<'_, T>, func: u32| {
+            check(&mut caller, get, |m| {
+                m.enter(func);
+                Ok(())
+            })
+        },
+    )?;
+    linker.func_wrap(HOOK_MODULE, EXIT_HOOK, move |mut caller: Caller<'_, T>| {
+        check(&mut caller, get, |m| {
+            m.exit();
+            Ok(())
+        })
+    })?;
+    linker.func_wrap(
+        HOOK_MODULE,
+        STACK_POINTER_HOOK,
+        move |mut caller: Caller<'_, T>, new_sp: u32| {
+            check(&mut caller, get, |m| m.stack_pointer(new_sp))
+        },
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+const GUEST: &str = r#"(module
+    (memory (export "memory") 1)
+    (global $__stack_pointer (mut i32) (i32.const 1024))
+    (global $bump (mut i32) (i32.const 4096))
+    (global $kept (mut i32) (i32.const 0))
+    (func $malloc (export "malloc") (param i32) (result i32)
+        (global.get $bump)
+        (global.set $bump (i32.add (global.get $bump) (local.get 0))))
+    (func $free (export "free") (param i32))
+    (func $use_after_free (export "use_after_free") (result i32)
+        (local $p i32)
+        (local.set $p (call $malloc (i32.const 16)))
+        (i32.store (local.get $p) (i32.const 1))
+        (call $free (local.get $p))
+        (i32.load (local.get $p)))
+    (func (export "copy_uninit") (result i32)
+        (local $src i32)
+        (local $dst i32)
+        (local.set $src (call $malloc (i32.const 8)))
+        (local.set $dst (call $malloc (i32.const 8)))
+        (memory.fill (local.get $src) (i32.const 0) (i32.const 4))
+        (memory.copy (local.get $dst) (local.get $src) (i32.const 8))
+        (drop (i32.load (local.get $dst)))
+        (i32.load (i32.add (local.get $dst) (i32.const 4))))
+    (func (export "branch_on_uninit") (result i32)
+        (local $p i32)
+        (local $x i32)
+        (local.set $p (call $malloc (i32.const 8)))
+        (i32.store (local.get $p) (i32.const 1))
+        ;; carrying the undefined half around is fine, even past a defined value
+        (block (result i32)
+            (i32.const 5)
+            (i32.load offset=4 (local.get $p))
+            (br_if 0 (i32.const 1))
+            (drop))
+        (local.set $x (i32.add (i32.const 1)))
+        (i32.store (local.get $p) (local.get $x))
+        (if (result i32) (i32.load (local.get $p))
+            (then (i32.const 1))
+            (else (i32.const 0))))
+    (func (export "grow_and_overflow")
+        (local $p i32)
+        (drop (memory.grow (i32.const 1)))
+        (local.set $p (call $malloc (i32.const 70000)))
+        (i32.store (i32.add (local.get $p) (i32.const 70000)) (i32.const 1)))
+    (func $local_address (result i32)
+        (global.set $__stack_pointer (i32.sub (global.get $__stack_pointer) (i32.const 16)))
+        (i32.store (global.get $__stack_pointer) (i32.const 1))
+        (global.get $__stack_pointer)
+        (global.set $__stack_pointer (i32.add (global.get $__stack_pointer) (i32.const 16))))
+    (func (export "use_after_return") (result i32)
+        (i32.load (call $local_address)))
+    (func (export "multi_value_block") (result i32)
+        (local $p i32)
+        (local.set $p (call $malloc (i32.const 8)))
+        (i32.store (local.get $p) (i32.const 1))
+        ;; an undefined and a defined value travel together
+        (block (result i32 i32)
+            (i32.load offset=4 (local.get $p))
+            (i32.load (local.get $p)))
+        (loop (param i32 i32) (result i32 i32))
+        (if (result i32) (then (i32.const 1)) (else (i32.const 0)))
+        (i32.add)
+        (if (result i32) (then (i32.const 1)) (else (i32.const 0))))
+    (func $load_uninit (result i32)
+        (i32.load (call $malloc (i32.const 4))))
+    (func (export "branch_on_result") (result i32)
+        (if (result i32) (call $load_uninit) (then (i32.const 1)) (else (i32.const 0))))
+    (func (export "keep_in_global") (result i32)
+        (global.set $kept (call $malloc (i32.const 8)))
+        (drop (call $malloc (i32.const 8)))
+        (i32.const 0))
+    (func $branch_on_param (param i32) (result i32)
+        (if (result i32) (local.get 0) (then (i32.const 1)) (else (i32.const 0))))
+    (func (export "pass_uninit") (result i32)
+        (call $branch_on_param (i32.load (call $malloc (i32.const 4)))))
+    (table funcref (elem $load_uninit))
+    (func (export "branch_on_indirect_result") (result i32)
+        (if (result i32) (call_indirect (result i32) (i32.const 0))
+            (then (i32.const 1))
+            (else (i32.const 0))))
+    (func (export "branch_on_moved")
+        (local $p i32)
+        (local $q i32)
+        (local.set $p (call $malloc (i32.const 4)))
+        (local.set $q (call $malloc (i32.const 4)))
+        (i32.store (local.get $q) (i32.load (local.get $p)))
+        (if (i32.load (local.get $q)) (then (nop)))))"#;
+
+#[cfg(test)]
+fn run_guest(
+    policy: ErrorPolicy,
+    export: &str,
+    suppressions: &str,
+) -> (anyhow::Result<()>, ValgrindCtx) {
+    run_guest_with(policy, export, suppressions, UndefinedChecks::OnLoad)
+}
+
+#[cfg(test)]
+fn run_guest_with(
+    policy: ErrorPolicy,
+    export: &str,
+    suppressions: &str,
+    checks: UndefinedChecks,
+) -> (anyhow::Result<()>, ValgrindCtx) {
+    use ::wasmtime::{Engine, Module, Store};
+
+    let mut config = instrument::Config::new();
+    config.set_allocator(instrument::AllocatorNames::default());
+    config.set_call_stacks(true);
+    // as the CLI does, moves come with value shadows
+    config.set_moves(checks != UndefinedChecks::OnLoad);
+    config.set_value_shadows(checks != UndefinedChecks::OnLoad);
+    let guest = wat::parse_str(GUEST).unwrap();
+    let wasm = instrument::instrument(&guest, &config).unwrap();
+    let layout = instrument::layout(&wasm, &config).unwrap();
+    let mut ctx = ValgrindCtx::new(layout, policy);
+    ctx.set_undefined_checks(checks);
+    ctx.set_suppressions(
+        Suppressions::parse(suppressions).unwrap(),
+        instrument::symbols(&guest).unwrap(),
+    );
+
+    let engine = Engine::default();
+    let module = Module::new(&engine, &wasm).unwrap();
+    let mut linker = Linker::new(&engine);
+    add_to_linker(&mut linker, |ctx: &mut ValgrindCtx| ctx).unwrap();
+    let mut store = Store::new(&engine, ctx);
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    let func = instance.get_func(&mut store, export).unwrap();
+    let mut results = vec![::wasmtime::Val::I32(0); func.ty(&store).results().len()];
+    let result = func.call(&mut store, &[], &mut results);
+    (result, store.into_data())
+}
+
+#[test]
+fn traps_on_error() {
+    let (result, ctx) = run_guest(ErrorPolicy::Trap, "use_after_free", "");
+    let err = result.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<AccessError>(),
+        Some(AccessError::UseAfterFree { addr: 4096, .. })
+    ));
+    assert!(ctx.error_log().is_none());
+    // the site of the trapping access is left for the embedder to report
+    assert!(ctx.monitor().unwrap().valgrind().site().is_some());
+}
+
+#[test]
+fn collects_errors() {
+    let (result, ctx) = run_guest(ErrorPolicy::Collect, "use_after_free", "");
+    assert!(result.is_ok());
+    let errors = ctx.error_log().unwrap().errors();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].error, AccessError::UseAfterFree { .. }));
+    assert!(errors[0].site.is_some());
+    // malloc and free were called from use_after_free, function 2
+    assert_eq!(errors[0].stack, [2]);
+    let block = errors[0].error.block().unwrap();
+    assert_eq!(
+        (&block.alloc_stack[..], &block.free_stack[..]),
+        (&[2][..], &[2][..])
+    );
+}
+
+#[test]
+fn follows_memory_growth() {
+    let (result, ctx) = run_guest(ErrorPolicy::Collect, "grow_and_overflow", "");
+    assert!(result.is_ok());
+    let errors = ctx.error_log().unwrap().errors();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(
+        errors[0].error,
+        AccessError::InvalidWrite { addr: 74096, .. }
+    ));
+}
+
+#[test]
+fn suppresses_by_function() {
+    let suppressions = "{\n  uaf\n  UseAfterFree\n  fun:use_after_*\n}";
+    let (result, ctx) = run_guest(ErrorPolicy::Trap, "use_after_free", suppressions);
+    assert!(result.is_ok());
+    let fired = ctx
+        .monitor()
+        .unwrap()
+        .valgrind()
+        .suppressions()
+        .unwrap()
+        .fired();
+    assert_eq!(fired.collect::<Vec<_>>(), [("uaf", 1)]);
+
+    let suppressions = "{\n  uaf\n  UseAfterFree\n  fun:grow_*\n}";
+    let (result, _) = run_guest(ErrorPolicy::Trap, "use_after_free", suppressions);
+    assert!(result.is_err());
+}
+
+#[test]
+fn copies_keep_uninitialized_bytes() {
+    let (result, ctx) = run_guest(ErrorPolicy::Trap, "copy_uninit", "");
+    let err = result.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<AccessError>(),
+        Some(AccessError::InvalidRead { bad_addr, .. }) if *bad_addr == 4096 + 8 + 4
+    ));
+    assert!(ctx.error_log().is_none());
+}
+
+#[test]
+fn branches_on_undefined_values() {
+    let (result, _) = run_guest(ErrorPolicy::Trap, "branch_on_uninit", "");
+    assert!(matches!(
+        result.unwrap_err().downcast_ref::<AccessError>(),
+        Some(AccessError::InvalidRead { bad_addr, .. }) if *bad_addr == 4096 + 4
+    ));
+
+    let checks = UndefinedChecks::OnUse(crate::UsePoints::default());
+    let (result, ctx) = run_guest_with(ErrorPolicy::Collect, "branch_on_uninit", "", checks);
+    assert!(result.is_ok());
+    let errors = ctx.error_log().unwrap().errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].error,
+        AccessError::UndefinedValue {
+            point: crate::ValueUse::Branch
+        }
+    );
+    assert_eq!(errors[0].stack, [4]);
+}
+
+#[test]
+fn reports_use_after_return() {
+    let (result, ctx) = run_guest(ErrorPolicy::Collect, "use_after_return", "");
+    assert!(result.is_ok());
+    let errors = ctx.error_log().unwrap().errors();
+    assert_eq!(errors.len(), 1);
+    // the frame was claimed by local_address, function 6
+    assert_eq!(
+        errors[0].error,
+        AccessError::UseAfterReturn {
+            addr: 1024 - 16,
+            len: 4,
+            frame: crate::StackFrame {
+                addr: 1024 - 16,
+                len: 16,
+                func: Some(6)
+            }
+        }
+    );
+    assert_eq!(errors[0].stack, [7]);
+}
+
+#[test]
+fn blocks_keep_values_apart() {
+    let checks = UndefinedChecks::OnUse(crate::UsePoints::default());
+    let (result, ctx) = run_guest_with(ErrorPolicy::Collect, "multi_value_block", "", checks);
+    assert!(result.is_ok());
+    // only the branch on the sum depends on the undefined value
+    let errors = ctx.error_log().unwrap().errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].error,
+        AccessError::UndefinedValue {
+            point: crate::ValueUse::Branch
+        }
+    );
+}
+
+#[test]
+fn calls_carry_definedness() {
+    let checks = UndefinedChecks::OnUse(crate::UsePoints::default());
+    // through a result, then through an argument, to a branch in the callee
+    for (export, site_func) in [("branch_on_result", 10), ("pass_uninit", 12)] {
+        let (result, ctx) = run_guest_with(ErrorPolicy::Collect, export, "", checks);
+        assert!(result.is_ok());
+        let errors = ctx.error_log().unwrap().errors();
+        assert_eq!(errors.len(), 1, "{}", export);
+        assert_eq!(
+            errors[0].error,
+            AccessError::UndefinedValue {
+                point: crate::ValueUse::Branch
+            }
+        );
+        assert_eq!(errors[0].stack.first(), Some(&site_func));
+    }
+}
+
+#[test]
+fn indirect_call_results_count_as_defined() {
+    // a known gap: shadows only pass through direct calls
+    let checks = UndefinedChecks::OnUse(crate::UsePoints::default());
+    let (result, ctx) = run_guest_with(
+        ErrorPolicy::Collect,
+        "branch_on_indirect_result",
+        "",
+        checks,
+    );
+    assert!(result.is_ok());
+    assert!(ctx.error_log().unwrap().errors().is_empty());
+}
+
+#[test]
+fn moves_keep_values_undefined() {
+    let checks = UndefinedChecks::OnUse(crate::UsePoints::default());
+    let (result, ctx) = run_guest_with(ErrorPolicy::Collect, "branch_on_moved", "", checks);
+    assert!(result.is_ok());
+    let errors = ctx.error_log().unwrap().errors();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].error,
+        AccessError::UndefinedValue {
+            point: crate::ValueUse::Branch
+        }
+    );
+}
+
+#[test]
+fn one_instance_per_context() {
+    use ::wasmtime::{Engine, Module, Store};
+
+    let config = instrument::Config::new();
+    let wasm = instrument::instrument(&wat::parse_str(GUEST).unwrap(), &config).unwrap();
+    let layout = instrument::layout(&wasm, &config).unwrap();
+    let engine = Engine::default();
+    let module = Module::new(&engine, &wasm).unwrap();
+    let mut linker = Linker::new(&engine);
+    add_to_linker(&mut linker, |ctx: &mut ValgrindCtx| ctx).unwrap();
+    let mut store = Store::new(&engine, ValgrindCtx::new(layout, ErrorPolicy::Collect));
+    let mut results = Vec::new();
+    let mut logged = Vec::new();
+    for _ in 0..2 {
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let func = instance.get_func(&mut store, "use_after_free").unwrap();
+        results.push(func.call(&mut store, &[], &mut [::wasmtime::Val::I32(0)]));
+        logged.push(store.data().error_log().unwrap().errors().len());
+    }
+    assert!(results[0].is_ok());
+    let err = results[1].as_ref().unwrap_err();
+    assert!(format!("{:?}", err).contains("second instance"));
+    // the second instance's accesses were not checked against the first one's memory
+    assert_eq!(logged[0], logged[1]);
+}
+
+#[test]
+fn globals_are_leak_roots() {
+    use ::wasmtime::{Engine, Module, Store};
+
+    let mut config = instrument::Config::new();
+    config.set_allocator(instrument::AllocatorNames::default());
+    config.set_global_roots(true);
+    let wasm = instrument::instrument(&wat::parse_str(GUEST).unwrap(), &config).unwrap();
+    let layout = instrument::layout(&wasm, &config).unwrap();
+    let engine = Engine::default();
+    let module = Module::new(&engine, &wasm).unwrap();
+    let mut linker = Linker::new(&engine);
+    add_to_linker(&mut linker, |ctx: &mut ValgrindCtx| ctx).unwrap();
+    let mut store = Store::new(&engine, ValgrindCtx::new(layout, ErrorPolicy::Collect));
+    let instance = linker.instantiate(&mut store, &module).unwrap();
+    let func = instance.get_func(&mut store, "keep_in_global").unwrap();
+    let mut results = [::wasmtime::Val::I32(0)];
+    assert!(func.call(&mut store, &[], &mut results).is_ok());
+
+    let roots = global_roots(&instance, &mut store);
+    assert!(roots.contains(&4096));
+    let memory = instance.get_memory(&mut store, "memory").unwrap();
+    let monitor = store.data().monitor().unwrap();
+    let report = monitor.valgrind().leak_check(memory.data(&store), &roots);
+    assert_eq!(report.still_reachable().blocks, 1);
+    assert_eq!(report.definitely_lost().blocks, 1);
+    assert_eq!(report.blocks[1].addr, 4104);
+}